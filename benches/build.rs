@@ -0,0 +1,68 @@
+//! Times `build` on a large chart and counts the allocations it makes.
+//!
+//! Run with `cargo bench --bench build`.
+
+use naive_rhythm::{build, parse, solve, BuildOptions, ParseOptions, SolveOptions};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const NOTES: usize = 1_000_000;
+const RUNS: u32 = 5;
+
+fn main() {
+    let mut chart = String::from("naive-rhythm bpm 120\n");
+    for i in 0..NOTES {
+        chart.push_str(&format!("{}:{}\n", i * 250, 36 + i % 8));
+    }
+    let input = parse(&chart, &ParseOptions::default()).unwrap();
+    let output = solve(input, &SolveOptions::default());
+    let mut elapsed = Vec::new();
+    let mut allocations = 0;
+    let mut bytes = 0;
+    for _ in 0..RUNS {
+        let output = output.clone();
+        ALLOCATIONS.store(0, Ordering::Relaxed);
+        BYTES.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        let binary = build(output, &BuildOptions::default()).unwrap();
+        elapsed.push(start.elapsed());
+        allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        bytes = BYTES.load(Ordering::Relaxed);
+        drop(binary);
+    }
+    elapsed.sort();
+    println!(
+        "build, {} notes: median {:?}, {} allocations, {} MiB allocated",
+        NOTES,
+        elapsed[elapsed.len() / 2],
+        allocations,
+        bytes / (1 << 20)
+    );
+}
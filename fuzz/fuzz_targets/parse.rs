@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use naive_rhythm::{parse, InputMode, MiddleC, ParseOptions};
+
+// any input may fail to parse, but none may panic
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = parse(s, &ParseOptions::default());
+    let headerless = ParseOptions {
+        headerless: true,
+        tempo: Some(500_000),
+        input_mode: InputMode::Relative,
+        middle_c: MiddleC::C3,
+    };
+    let _ = parse(s, &headerless);
+});
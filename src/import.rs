@@ -0,0 +1,100 @@
+use crate::{error_kinds, timing, Bpm, Pitch, Tempo, Velocity, MAGICS, MAX_BPM, MIN_BPM};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use std::fmt::Write;
+use thiserror::Error;
+
+#[derive(Default)]
+pub struct ImportOptions {
+    /// Name the gap before each note as a musical duration in a comment.
+    pub label_durations: bool,
+}
+
+error_kinds! {
+    #[derive(Error, Debug)]
+    pub enum ImportError {
+        #[error("bad MIDI file: {0}")]
+        Midi(#[from] midly::Error),
+        #[error("timecode-based MIDI files are not supported")]
+        Timecode,
+    }
+}
+
+/// Musical durations in beats, and the names `label` gives them.
+const DURATIONS: [(f64, &str); 10] = [
+    (4.0, "whole"),
+    (2.0, "half"),
+    (1.5, "dotted quarter"),
+    (1.0, "quarter"),
+    (2.0 / 3.0, "quarter triplet"),
+    (0.5, "eighth"),
+    (1.0 / 3.0, "eighth triplet"),
+    (0.25, "sixteenth"),
+    (1.0 / 6.0, "sixteenth triplet"),
+    (0.125, "thirty-second"),
+];
+
+/// How far, relative to a duration, a gap may be from it and still be named after it.
+const TOLERANCE: f64 = 0.05;
+
+/// Names a gap of `beats` beats after the nearest musical duration, if one is close enough.
+fn label(beats: f64) -> Option<&'static str> {
+    DURATIONS
+        .iter()
+        .filter(|&&(length, _)| (beats - length).abs() <= length * TOLERANCE)
+        .min_by(|a, b| (beats - a.0).abs().total_cmp(&(beats - b.0).abs()))
+        .map(|&(_, name)| name)
+}
+
+/// Turns the notes of every track back into one chart.
+///
+/// Only NoteOns are read, so any track layout and any other events are fine, and a track may
+/// end without an EndOfTrack.
+pub fn import(bytes: &[u8], options: &ImportOptions) -> Result<String, ImportError> {
+    let smf = Smf::parse(bytes)?;
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int(),
+        Timing::Timecode(..) => return Err(ImportError::Timecode),
+    };
+    let tempo: Tempo = smf
+        .tracks
+        .iter()
+        .flatten()
+        .find_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) if tempo > 0 => Some(tempo.as_int()),
+            _ => None,
+        })
+        .unwrap_or(500_000);
+    let mut notes: Vec<(u64, Pitch, Velocity)> = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            if let TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } = event.kind
+            {
+                if vel > 0 {
+                    notes.push((tick, key.as_int(), vel.as_int()));
+                }
+            }
+        }
+    }
+    notes.sort_by_key(|&(tick, _, _)| tick);
+    let bpm = ((60_000.0 / timing::beat_ms(tempo)).round() as Bpm).clamp(MIN_BPM, MAX_BPM);
+    let mut chart = format!("{}\nbpm {}\n", MAGICS[0], bpm);
+    let mut previous = None;
+    for (tick, pitch, velocity) in notes {
+        let ms = timing::ticks_to_ms(tick, tempo, ppq);
+        write!(chart, "{}:{}:{}", ms, pitch, velocity).unwrap();
+        if let Some(previous) = previous.filter(|_| options.label_durations) {
+            let beats = (tick - previous) as f64 / ppq as f64;
+            if let Some(name) = label(beats) {
+                write!(chart, " # {}", name).unwrap();
+            }
+        }
+        chart.push('\n');
+        previous = Some(tick);
+    }
+    Ok(chart)
+}
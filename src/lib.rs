@@ -0,0 +1,2270 @@
+use midly::num::{u14, u15, u24, u28, u4, u7};
+use midly::{
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use thiserror::Error;
+
+mod import;
+mod report;
+pub mod timing;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use import::{import, ImportError, ImportOptions};
+pub use report::Report;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OutOfRange {
+    /// Fail the conversion
+    #[default]
+    Error,
+    /// Use 127 instead
+    Clamp,
+    /// Move the note down by octaves until it fits
+    OctaveWrap,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DedupBy {
+    /// Keep a single note per beat
+    Time,
+    /// Keep one note per beat and pitch
+    #[default]
+    Note,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DedupKeep {
+    /// The one listed first in the chart
+    #[default]
+    First,
+    /// The one with the highest velocity, or the first of those
+    Loudest,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum InputMode {
+    /// A key is the note's time in ms
+    #[default]
+    Absolute,
+    /// A key is the gap in ms since the previous note, or since zero for the first
+    Relative,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MiddleC {
+    /// Middle C is C4, as in scientific pitch notation
+    #[default]
+    C4,
+    /// Middle C is C3, as on many keyboards and DAWs
+    C3,
+}
+
+#[derive(Default)]
+pub struct ParseOptions {
+    /// The chart has no magic or bpm header, only keys and directives.
+    pub headerless: bool,
+    /// Tempo to use instead of the chart's; required for headerless charts.
+    pub tempo: Option<Tempo>,
+    /// How keys are read; directive times are always absolute.
+    pub input_mode: InputMode,
+    /// Octave numbering of note names.
+    pub middle_c: MiddleC,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Quantize {
+    /// Snap where notes start, keeping written durations exact
+    #[default]
+    Onset,
+    /// Snap both where notes start and how long they last
+    Both,
+    /// Snap how long notes last, keeping their start exact
+    Duration,
+}
+
+pub struct SolveOptions {
+    pub dedup_by: DedupBy,
+    pub dedup_keep: DedupKeep,
+    /// Reference beat times to snap to instead of a regular grid, sorted.
+    pub grid: Option<Vec<Key>>,
+    /// Follow the reference times with a tempo change on every beat, instead of keeping the
+    /// chart's tempo.
+    pub grid_tempo: bool,
+    /// Tempos in bpm by the time in ms they start at, sorted; with more than one, notes snap
+    /// to the beats they lay out and the output follows them, as with `grid_tempo`.
+    pub tempo_map: Option<Vec<(Key, Bpm)>>,
+    /// Steps per beat of the regular grid, raised when a time signature's beat is shorter than a
+    /// step; only that signature's stretch snaps to the finer steps.
+    pub division: u32,
+    /// Which of a note's start and written duration are snapped.
+    pub quantize: Quantize,
+    /// Which step a time exactly between two snaps to.
+    pub tie_break: TieBreak,
+    /// Thin a roll of more than this many notes of one pitch in a beat down to its first note.
+    pub collapse_rolls: Option<u32>,
+    /// Move a duplicate to the next free step instead of dropping it.
+    pub spread: bool,
+    /// Keep only the chart from this time in ms, moved to start at zero.
+    pub from: Option<Key>,
+    /// Keep only the chart before this time in ms.
+    pub to: Option<Key>,
+    /// What happens to a written duration that crosses `from` or `to`.
+    pub clip: Clip,
+}
+
+impl Default for SolveOptions {
+    fn default() -> Self {
+        SolveOptions {
+            dedup_by: DedupBy::Note,
+            dedup_keep: DedupKeep::First,
+            grid: None,
+            grid_tempo: false,
+            tempo_map: None,
+            division: 1,
+            quantize: Quantize::Onset,
+            tie_break: TieBreak::Down,
+            collapse_rolls: None,
+            spread: false,
+            from: None,
+            to: None,
+            clip: Clip::Truncate,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TieBreak {
+    /// The earlier step
+    #[default]
+    Down,
+    /// The later step
+    Up,
+    /// Whichever step is even
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum NoteOffStyle {
+    /// Explicit NoteOff messages
+    #[default]
+    Off,
+    /// NoteOn messages with velocity 0, which keep running status intact
+    ZeroVel,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Overlap {
+    /// End the sounding note where the new one starts
+    #[default]
+    Truncate,
+    /// Drop the new note and hold the sounding one until the later of their ends
+    Merge,
+    /// End the sounding note where the new one starts, and hold the new one until the later of
+    /// their ends
+    Retrigger,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LongGap {
+    /// Fail the conversion
+    #[default]
+    Error,
+    /// Carry the gap over empty text events, each as long as a delta can be
+    Split,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Clip {
+    /// Cut a note down to the part of it inside the range
+    #[default]
+    Truncate,
+    /// Leave out a note that is not wholly inside the range
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum VelocityCurve {
+    /// Velocities as written
+    #[default]
+    Linear,
+    /// Square the velocity, softening everything below full
+    Exp,
+    /// Take the square root of the velocity, lifting soft notes
+    Log,
+}
+
+/// A metronome sound: a percussion pitch and how hard to hit it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Click {
+    pub pitch: Pitch,
+    pub velocity: Velocity,
+}
+
+/// Sounds for a metronome that clicks on every beat of the time signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metronome {
+    /// The first beat of each bar.
+    pub accent: Click,
+    /// Every other beat.
+    pub beat: Click,
+    /// Milliseconds to keep clicking for even past the last note, such as for a click track
+    /// with no notes at all.
+    pub length: u32,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Metronome {
+            accent: Click {
+                pitch: 76,
+                velocity: 127,
+            },
+            beat: Click {
+                pitch: 77,
+                velocity: 100,
+            },
+            length: 0,
+        }
+    }
+}
+
+/// Where the notes of one lane are played: a channel, counting from 0, and a GM program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LaneMap {
+    pub lane: u8,
+    pub channel: u8,
+    pub program: u8,
+}
+
+pub struct BuildOptions {
+    pub note_off_style: NoteOffStyle,
+    /// What happens when a note starts while the same pitch is still sounding on its channel.
+    pub overlap: Overlap,
+    pub velocity_curve: VelocityCurve,
+    /// Scale every velocity, after the curve, so the loudest note plays at this one.
+    pub normalize_velocity: Option<Velocity>,
+    pub min_duration_ticks: u32,
+    pub split_by_pitch: bool,
+    pub out_of_range: OutOfRange,
+    pub lead_in: u32,
+    /// Controller events per beat for each LFO.
+    pub lfo_resolution: u32,
+    /// Milliseconds of silence after the last event of each note track, for the end to ring.
+    pub tail: u32,
+    /// What to do with a gap between events of more than 2^28 - 1 ticks.
+    pub long_gap: LongGap,
+    pub gm_reset: bool,
+    /// Make the file as small as possible: zero-velocity NoteOns end notes, so running status
+    /// never breaks, and the empty track name is left out.
+    pub compact: bool,
+    /// Add a metronome track on the GM percussion channel.
+    pub metronome: Option<Metronome>,
+    /// Channels and programs for lanes; unmapped lanes keep their chart's channel.
+    pub lane_map: Vec<LaneMap>,
+    /// Let several lanes map to one channel, as long as they agree on the program, and a lane
+    /// map onto the channel of a lane left unmapped, which then plays its program.
+    pub share_channels: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            note_off_style: NoteOffStyle::Off,
+            overlap: Overlap::Truncate,
+            velocity_curve: VelocityCurve::Linear,
+            normalize_velocity: None,
+            min_duration_ticks: 1,
+            split_by_pitch: false,
+            out_of_range: OutOfRange::Error,
+            lead_in: 0,
+            lfo_resolution: 8,
+            tail: 0,
+            long_gap: LongGap::Error,
+            gm_reset: false,
+            compact: false,
+            metronome: None,
+            lane_map: Vec::new(),
+            share_channels: false,
+        }
+    }
+}
+
+/// Everything `convert` needs to know besides the chart itself.
+#[derive(Default)]
+pub struct Opts {
+    pub parse: ParseOptions,
+    pub solve: SolveOptions,
+    pub build: BuildOptions,
+}
+
+pub type Bpm = u32;
+/// Microseconds per quarter note.
+pub type Tempo = u32;
+/// Milliseconds from the chart's reference zero; negative for pre-roll.
+pub type Key = i64;
+pub type Pitch = u8;
+pub type Velocity = u8;
+
+/// Slowest tempo a MIDI Tempo event can hold.
+pub const MAX_TEMPO: Tempo = 0xFF_FFFF;
+/// Fastest tempo that still has a beat of at least one millisecond.
+pub const MIN_TEMPO: Tempo = 1000;
+pub const MIN_BPM: Bpm = 60_000_000 / MAX_TEMPO + 1;
+pub const MAX_BPM: Bpm = 60_000_000 / MIN_TEMPO;
+/// Largest distance of a key from zero, in either direction.
+pub const MAX_KEY: Key = u32::MAX as Key;
+
+pub const DEFAULT_PITCH: Pitch = 60;
+pub const DEFAULT_VELOCITY: Velocity = 127;
+/// Pitch bend range in semitones either way, the General MIDI default.
+pub const BEND_RANGE: f64 = 2.0;
+/// Control change number of the sustain pedal.
+pub const SUSTAIN: u8 = 64;
+/// Channel General MIDI reserves for percussion, counting from 0.
+pub const PERCUSSION_CHANNEL: u8 = 9;
+/// Divisions `best_division` chooses from, coarsest first.
+pub const AUTO_DIVISIONS: [u32; 6] = [1, 2, 3, 4, 6, 8];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub key: Key,
+    pub pitch: Pitch,
+    pub velocity: Velocity,
+    pub channel: u8,
+    /// Part of the chart the note belongs to, set with `@lane`.
+    pub lane: u8,
+    /// Written duration in ms; without one a note lasts until the next.
+    pub duration: Option<Key>,
+}
+
+/// How long a note with a written duration lasts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hold {
+    Ms(Key),
+    /// Grid steps, see `Output::division`.
+    Steps(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hit {
+    /// Grid step, see `Output::division`.
+    pub beat: u64,
+    /// Where the note was written, in ms from the start of the chart.
+    pub key: Key,
+    /// Where the note starts in ms when onsets are not snapped.
+    pub exact: Option<Key>,
+    pub duration: Option<Hold>,
+    pub lane: u8,
+    pub pitch: Pitch,
+    pub velocity: Velocity,
+    pub channel: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+impl TimeSignature {
+    pub const COMMON: TimeSignature = TimeSignature {
+        numerator: 4,
+        denominator: 4,
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// 14-bit pitch bend, 8192 being no bend.
+    PitchBend(u16),
+    /// Channel pressure, for the whole channel.
+    Aftertouch(u8),
+    /// Polyphonic key pressure, for one pitch.
+    PolyAftertouch { pitch: Pitch, value: u8 },
+    /// Control change, such as the sustain pedal.
+    Controller { controller: u8, value: u8 },
+    /// Program change, choosing the channel's instrument.
+    Program(u8),
+}
+
+/// A channel message at an exact time rather than on the grid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub key: Key,
+    pub channel: u8,
+    pub kind: EventKind,
+}
+
+/// A control change swept up and down around its middle for the whole chart, in time with the
+/// beat.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lfo {
+    pub channel: u8,
+    pub controller: u8,
+    /// Beats per sweep up and back down.
+    pub rate: f64,
+    /// How far the value swings either side of 64.
+    pub depth: u8,
+}
+
+/// Authoring information from the lines between the magic and the bpm.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+pub struct Input {
+    pub tempo: Tempo,
+    pub notes: Vec<Note>,
+    pub time_signatures: Vec<(Key, TimeSignature)>,
+    pub events: Vec<Event>,
+    pub lfos: Vec<Lfo>,
+    /// Milliseconds added to every time so none is negative.
+    pub shift: Key,
+    pub metadata: Metadata,
+    /// Where the chart ends when it says, which may be after its last note.
+    pub end: Option<Key>,
+    /// Problems that did not stop the chart from parsing, for the caller to report.
+    pub warnings: Vec<String>,
+}
+
+impl Input {
+    /// Moves every time in the chart later by `by` milliseconds.
+    fn shift_by(&mut self, by: Key) {
+        for note in &mut self.notes {
+            note.key += by;
+        }
+        for (at, _) in &mut self.time_signatures {
+            *at += by;
+        }
+        for event in &mut self.events {
+            event.key += by;
+        }
+        if let Some(end) = &mut self.end {
+            *end += by;
+        }
+        self.shift += by;
+    }
+}
+
+#[derive(Clone)]
+pub struct Output {
+    pub tempo: Tempo,
+    /// Later tempos by beat, sorted; times in ms follow them.
+    pub tempo_changes: Vec<(u64, Tempo)>,
+    pub metadata: Metadata,
+    /// Grid steps per beat; every beat in the output counts these steps.
+    pub division: u32,
+    /// Sorted by beat, then by channel, lane and pitch.
+    pub notes: Vec<Hit>,
+    /// Time signature changes by beat, sorted, always starting at beat 0.
+    pub time_signatures: Vec<(u64, TimeSignature)>,
+    /// Sorted by time, which is in milliseconds and not snapped.
+    pub events: Vec<Event>,
+    pub lfos: Vec<Lfo>,
+    /// How many notes were dropped as duplicates of another, by beat, sorted.
+    pub merged: Vec<(u64, u32)>,
+    /// Reference times of the beats when snapping to a grid, in ms from the start of the chart.
+    pub grid: Option<Vec<Key>>,
+    /// The step the chart ends on when it says, which may be after its last note.
+    pub end: Option<u64>,
+}
+
+impl Output {
+    /// Where a grid step falls in ms from the start of the chart, going on at `tempo` past the
+    /// last reference time.
+    pub fn step_ms(&self, beat: u64) -> f64 {
+        let step_ms = timing::beat_ms(self.tempo) / self.division as f64;
+        match self.grid.as_deref() {
+            Some(grid) if !grid.is_empty() => {
+                let last = grid.len() - 1;
+                match grid.get(beat as usize) {
+                    Some(&time) => time as f64,
+                    None => grid[last] as f64 + (beat as usize - last) as f64 * step_ms,
+                }
+            }
+            _ => beat as f64 * step_ms,
+        }
+    }
+}
+
+error_kinds! {
+    #[derive(Error, Debug)]
+    pub enum ParseError {
+        #[error("bad magic")]
+        BadMagic,
+        #[error("bad bpm")]
+        BadBpm,
+        #[error("bad key time")]
+        BadKey,
+        #[error("bad pitch")]
+        BadPitch,
+        #[error("bad velocity")]
+        BadVelocity,
+        #[error("bad duration")]
+        BadDuration,
+        #[error("bad time signature")]
+        BadTimeSignature,
+        #[error("bad pitch bend")]
+        BadBend,
+        #[error("bad aftertouch")]
+        BadAftertouch,
+        #[error("bad pedal")]
+        BadPedal,
+        #[error("bad lane")]
+        BadLane,
+        #[error("bad lane map")]
+        BadLaneMap,
+        #[error("bad end")]
+        BadEnd,
+        #[error("bad lfo")]
+        BadLfo,
+        #[error("unknown directive")]
+        UnknownDirective,
+        #[error("inputs have different tempos")]
+        TempoMismatch,
+        #[error("more inputs than MIDI channels")]
+        TooManyInputs,
+        #[error("no inputs to merge")]
+        NoInputs,
+        #[error("bad grid time")]
+        BadGrid,
+        #[error("bad tempo file")]
+        BadTempoFile,
+        #[error("no tempo for a headerless chart")]
+        MissingTempo,
+    }
+}
+
+error_kinds! {
+    #[derive(Error, Debug)]
+    pub enum OutputError {
+        #[error("buffer error")]
+        IOError(#[from] std::io::Error),
+        #[error("pitch {0} is out of the MIDI range")]
+        PitchOutOfRange(Pitch),
+        #[error("lane {0} is mapped more than once")]
+        LaneMappedTwice(u8),
+        #[error("channel {0} is mapped from more than one lane")]
+        SharedChannel(u8),
+        #[error("a gap of {0} ticks is too long for a MIDI delta")]
+        GapTooLong(u64),
+    }
+}
+
+/// Where in a chart parsing failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Place {
+    /// Counting from 1.
+    pub line: usize,
+    /// The token being read, such as `0:300`.
+    pub token: String,
+}
+
+/// A parse error in a chart, with the place it happened when the chart had a token to blame.
+#[derive(Debug)]
+pub struct ChartError {
+    pub error: ParseError,
+    pub place: Option<Place>,
+}
+
+impl std::fmt::Display for ChartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.place {
+            Some(place) => write!(
+                f,
+                "{} on line {} at {:?}",
+                self.error, place.line, place.token
+            ),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for ChartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl Report for ChartError {
+    fn kind(&self) -> String {
+        self.error.kind()
+    }
+
+    fn place(&self) -> Option<&Place> {
+        self.place.as_ref()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to parse the input: {0}")]
+    Parse(#[from] ChartError),
+    #[error("failed to build the output: {0}")]
+    Output(#[from] OutputError),
+}
+
+/// Named after the parse or output error inside.
+impl Report for Error {
+    fn kind(&self) -> String {
+        match self {
+            Error::Parse(e) => e.kind(),
+            Error::Output(e) => e.kind(),
+        }
+    }
+
+    fn place(&self) -> Option<&Place> {
+        match self {
+            Error::Parse(e) => e.place(),
+            Error::Output(_) => None,
+        }
+    }
+}
+
+/// Splits on whitespace and commas, skipping comments.
+fn tokenize(s: &str) -> impl Iterator<Item = &str> {
+    s.lines().flat_map(line_tokens)
+}
+
+fn line_tokens(line: &str) -> impl Iterator<Item = &str> {
+    strip_comment(line)
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+}
+
+/// Cuts a line at its comment, a `#` anywhere but straight after the note letter that starts a
+/// pitch field, so that `0:D#5` keeps its sharp while `1200# kick` is still a key and a comment.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = [None, None];
+    for (i, c) in line.char_indices() {
+        let sharp = match previous {
+            [Some(':'), Some(letter)] => matches!(letter, 'A'..='G' | 'a'..='g'),
+            _ => false,
+        };
+        if c == '#' && !sharp {
+            return &line[..i];
+        }
+        previous = [previous[1], Some(c)];
+    }
+    line
+}
+
+/// Splits a `key: value` metadata line, if it is one.
+fn metadata_entry(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = strip_comment(line).split_once(':')?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((key, value.trim()))
+}
+
+/// Accepted spellings of the first token of a chart.
+pub const MAGICS: [&str; 2] = ["naive-rhythm", "nr"];
+
+pub fn parse(s: &str, options: &ParseOptions) -> Result<Input, ChartError> {
+    // the last token read, which is the one a failure is on
+    let last = Cell::new(None);
+    let parsed = match (options.headerless, options.tempo) {
+        (true, Some(tempo)) => {
+            let tokens = tokenize(s).inspect(|token| last.set(Some(*token)));
+            parse_body(tempo, tokens, options)
+        }
+        (true, None) => Err(ParseError::MissingTempo),
+        (false, _) => parse_chart(s, options, &last),
+    };
+    let mut input = parsed.map_err(|error| ChartError {
+        error,
+        place: last.get().map(|token| place(s, token)),
+    })?;
+    if let Some(tempo) = options.tempo {
+        input.tempo = tempo;
+    }
+    Ok(input)
+}
+
+/// Finds the line of `s` that `token`, a slice of it, is on.
+fn place(s: &str, token: &str) -> Place {
+    let offset = token.as_ptr() as usize - s.as_ptr() as usize;
+    Place {
+        line: s[..offset].matches('\n').count() + 1,
+        token: token.to_owned(),
+    }
+}
+
+/// Reads a chart with its header, keeping the token it has reached in `last`.
+fn parse_chart<'a>(
+    s: &'a str,
+    options: &ParseOptions,
+    last: &Cell<Option<&'a str>>,
+) -> Result<Input, ParseError> {
+    use ParseError::*;
+    let mut lines = s.lines().filter(|line| line_tokens(line).next().is_some());
+    let mut first = line_tokens(lines.next().ok_or(BadMagic)?);
+    // magic
+    let magic = first.next().ok_or(BadMagic)?;
+    last.set(Some(magic));
+    if !MAGICS.contains(&magic) {
+        return Err(BadMagic);
+    }
+    // metadata, only when the magic has a line to itself
+    let mut first = first.peekable();
+    let mut lines = lines.peekable();
+    let mut metadata = Metadata::default();
+    let mut warnings = Vec::new();
+    if first.peek().is_none() {
+        while let Some((key, value)) = lines.peek().copied().and_then(metadata_entry) {
+            match key {
+                "title" => metadata.title = Some(value.to_owned()),
+                "artist" => metadata.artist = Some(value.to_owned()),
+                _ => warnings.push(format!("unknown metadata key {:?}", key)),
+            }
+            lines.next();
+        }
+    }
+    let mut tokens = first
+        .chain(lines.flat_map(line_tokens))
+        .inspect(|token| last.set(Some(*token)));
+    // bpm
+    if "bpm" != tokens.next().ok_or(BadBpm)? {
+        return Err(BadBpm);
+    }
+    let bpm_str = tokens.next().ok_or(BadBpm)?;
+    let bpm: Bpm = bpm_str.parse().map_err(|_| BadBpm)?;
+    if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+        return Err(BadBpm);
+    }
+    let mut input = parse_body(timing::tempo_us(bpm), tokens, options)?;
+    input.metadata = metadata;
+    warnings.append(&mut input.warnings);
+    input.warnings = warnings;
+    Ok(input)
+}
+
+/// Reads the keys and directives that follow the header.
+fn parse_body<'a>(
+    tempo: Tempo,
+    mut tokens: impl Iterator<Item = &'a str>,
+    options: &ParseOptions,
+) -> Result<Input, ParseError> {
+    use ParseError::*;
+    let mut notes = Vec::new();
+    // the previous note's time, for relative keys
+    let mut previous = 0;
+    let mut time_signatures = Vec::new();
+    let mut events = Vec::new();
+    let mut lane = 0;
+    let mut lfos = Vec::new();
+    let mut end = None;
+    while let Some(token) = tokens.next() {
+        if let Some(directive) = token.strip_prefix('@') {
+            match directive {
+                "timesig" => {
+                    let signature = parse_time_signature(tokens.next().ok_or(BadTimeSignature)?)?;
+                    if "at" != tokens.next().ok_or(BadTimeSignature)? {
+                        return Err(BadTimeSignature);
+                    }
+                    let at = parse_time(tokens.next(), BadTimeSignature)?;
+                    time_signatures.push((at, signature));
+                }
+                "bend" => {
+                    let key = parse_time(tokens.next(), BadBend)?;
+                    let value = parse_bend(tokens.next().ok_or(BadBend)?)?;
+                    events.push(Event {
+                        key,
+                        channel: 0,
+                        kind: EventKind::PitchBend(value),
+                    });
+                }
+                "aftertouch" => {
+                    let key = parse_time(tokens.next(), BadAftertouch)?;
+                    let value = parse_data_byte(tokens.next(), BadAftertouch)?;
+                    events.push(Event {
+                        key,
+                        channel: 0,
+                        kind: EventKind::Aftertouch(value),
+                    });
+                }
+                "poly" => {
+                    let key = parse_time(tokens.next(), BadAftertouch)?;
+                    let pitch = parse_data_byte(tokens.next(), BadAftertouch)?;
+                    let value = parse_data_byte(tokens.next(), BadAftertouch)?;
+                    events.push(Event {
+                        key,
+                        channel: 0,
+                        kind: EventKind::PolyAftertouch { pitch, value },
+                    });
+                }
+                "lane" => {
+                    lane = tokens.next().and_then(|s| s.parse().ok()).ok_or(BadLane)?;
+                }
+                "pedal" => {
+                    let value = match tokens.next().ok_or(BadPedal)? {
+                        "down" => 127,
+                        "up" => 0,
+                        _ => return Err(BadPedal),
+                    };
+                    let key = parse_time(tokens.next(), BadPedal)?;
+                    events.push(Event {
+                        key,
+                        channel: 0,
+                        kind: EventKind::Controller {
+                            controller: SUSTAIN,
+                            value,
+                        },
+                    });
+                }
+                "end" => end = Some(parse_time(tokens.next(), BadEnd)?),
+                "lfo" => {
+                    let controller = parse_data_byte(tokens.next(), BadLfo)?;
+                    let rate = tokens
+                        .next()
+                        .and_then(|s| s.parse().ok())
+                        .filter(|&rate: &f64| rate.is_finite() && rate > 0.0)
+                        .ok_or(BadLfo)?;
+                    let depth = parse_data_byte(tokens.next(), BadLfo)?;
+                    // 64 either way would reach 128
+                    if depth > 63 {
+                        return Err(BadLfo);
+                    }
+                    lfos.push(Lfo {
+                        channel: 0,
+                        controller,
+                        rate,
+                        depth,
+                    });
+                }
+                _ => return Err(UnknownDirective),
+            }
+            continue;
+        }
+        let note = parse_note(token, options.middle_c)?;
+        let key = match options.input_mode {
+            InputMode::Absolute => note.key,
+            InputMode::Relative => previous + note.key,
+        };
+        if key.unsigned_abs() > MAX_KEY as u64 {
+            return Err(BadKey);
+        }
+        previous = key;
+        notes.push(Note { key, lane, ..note });
+    }
+    let mut warnings = Vec::new();
+    let last = notes.iter().map(|note| note.key).max();
+    if let (Some(last), Some(end)) = (last, end) {
+        if last > end {
+            warnings.push(format!(
+                "a note at {} ms is after the end at {} ms",
+                last, end
+            ));
+        }
+    }
+    // input
+    let mut input = Input {
+        tempo,
+        notes,
+        time_signatures,
+        events,
+        lfos,
+        shift: 0,
+        metadata: Metadata::default(),
+        end,
+        warnings,
+    };
+    // pre-roll: start the chart at its earliest time instead of before zero
+    let earliest = input
+        .notes
+        .iter()
+        .map(|note| note.key)
+        .chain(input.time_signatures.iter().map(|&(at, _)| at))
+        .chain(input.events.iter().map(|event| event.key))
+        .min()
+        .unwrap_or(0);
+    if earliest < 0 {
+        input.shift_by(-earliest);
+    }
+    Ok(input)
+}
+
+/// Combines charts into one, putting the notes of each on its own channel.
+///
+/// Charts are aligned on their reference zero, so the merged shift is the largest of theirs.
+pub fn merge(inputs: Vec<Input>) -> Result<Input, ParseError> {
+    use ParseError::*;
+    if inputs.is_empty() {
+        return Err(NoInputs);
+    }
+    if inputs.len() > 16 {
+        return Err(TooManyInputs);
+    }
+    let shift = inputs.iter().map(|input| input.shift).max().unwrap_or(0);
+    let mut merged = Input {
+        tempo: inputs[0].tempo,
+        notes: Vec::new(),
+        time_signatures: Vec::new(),
+        events: Vec::new(),
+        lfos: Vec::new(),
+        shift,
+        metadata: Metadata::default(),
+        end: None,
+        warnings: Vec::new(),
+    };
+    for (channel, mut input) in inputs.into_iter().enumerate() {
+        if input.tempo != merged.tempo {
+            return Err(TempoMismatch);
+        }
+        input.shift_by(shift - input.shift);
+        let notes = input.notes.into_iter().map(|note| Note {
+            channel: channel as u8,
+            ..note
+        });
+        merged.notes.extend(notes);
+        merged.time_signatures.extend(input.time_signatures);
+        // the first chart to say wins
+        merged.metadata.title = merged.metadata.title.or(input.metadata.title);
+        merged.metadata.artist = merged.metadata.artist.or(input.metadata.artist);
+        merged.end = merged.end.max(input.end);
+        merged.warnings.extend(input.warnings);
+        let events = input.events.into_iter().map(|event| Event {
+            channel: channel as u8,
+            ..event
+        });
+        merged.events.extend(events);
+        let lfos = input.lfos.into_iter().map(|lfo| Lfo {
+            channel: channel as u8,
+            ..lfo
+        });
+        merged.lfos.extend(lfos);
+    }
+    Ok(merged)
+}
+
+/// Parses `KEY[:PITCH[:VELOCITY[:DURATION]]]`, where an empty field takes its default and the
+/// pitch may be a note name.
+fn parse_note(s: &str, middle_c: MiddleC) -> Result<Note, ParseError> {
+    use ParseError::*;
+    let mut fields = s.split(':');
+    let key_str = fields.next().unwrap_or_default();
+    let pitch_str = fields.next().filter(|s| !s.is_empty());
+    let velocity_str = fields.next().filter(|s| !s.is_empty());
+    let duration_str = fields.next().filter(|s| !s.is_empty());
+    if fields.next().is_some() {
+        return Err(BadKey);
+    }
+    let key: Key = key_str.parse().map_err(|_| BadKey)?;
+    if key.unsigned_abs() > MAX_KEY as u64 {
+        return Err(BadKey);
+    }
+    let pitch = match pitch_str {
+        Some(pitch_str) => parse_pitch(pitch_str, middle_c)?,
+        None => DEFAULT_PITCH,
+    };
+    let velocity = match velocity_str {
+        Some(velocity_str) => velocity_str.parse().map_err(|_| BadVelocity)?,
+        None => DEFAULT_VELOCITY,
+    };
+    // a NoteOn with velocity 0 is a NoteOff
+    if !(1..=127).contains(&velocity) {
+        return Err(BadVelocity);
+    }
+    let duration = match duration_str {
+        Some(duration_str) => Some(
+            duration_str
+                .parse()
+                .ok()
+                .filter(|duration| (1..=MAX_KEY).contains(duration))
+                .ok_or(BadDuration)?,
+        ),
+        None => None,
+    };
+    Ok(Note {
+        key,
+        pitch,
+        velocity,
+        channel: 0,
+        lane: 0,
+        duration,
+    })
+}
+
+/// Parses a directive's time in ms, failing with `error`.
+fn parse_time(s: Option<&str>, error: ParseError) -> Result<Key, ParseError> {
+    s.and_then(|s| s.parse().ok())
+        .filter(|at: &Key| at.unsigned_abs() <= MAX_KEY as u64)
+        .ok_or(error)
+}
+
+/// Parses a 7-bit MIDI value, failing with `error`.
+fn parse_data_byte(s: Option<&str>, error: ParseError) -> Result<u8, ParseError> {
+    s.and_then(|s| s.parse().ok())
+        .filter(|&value: &u8| value <= 127)
+        .ok_or(error)
+}
+
+/// Parses a pitch bend, either a raw 14-bit value or, with a decimal point, semitones.
+fn parse_bend(s: &str) -> Result<u16, ParseError> {
+    let value = if s.contains('.') {
+        let semitones: f64 = s.parse().map_err(|_| ParseError::BadBend)?;
+        // there is one step less above the centre than below it
+        let steps = if semitones > 0.0 { 8191.0 } else { 8192.0 };
+        (8192.0 + semitones / BEND_RANGE * steps).round()
+    } else {
+        s.parse::<u16>().map_err(|_| ParseError::BadBend)? as f64
+    };
+    if !(0.0..=16383.0).contains(&value) {
+        return Err(ParseError::BadBend);
+    }
+    Ok(value as u16)
+}
+
+/// Parses a lane mapping written as `LANE:CHANNEL:PROGRAM`.
+pub fn parse_lane_map(s: &str) -> Result<LaneMap, ParseError> {
+    let fields: Vec<&str> = s.split(':').collect();
+    let [lane, channel, program] = fields[..] else {
+        return Err(ParseError::BadLaneMap);
+    };
+    let lane = lane.parse().map_err(|_| ParseError::BadLaneMap)?;
+    let channel = channel
+        .parse()
+        .ok()
+        .filter(|&channel: &u8| channel < 16)
+        .ok_or(ParseError::BadLaneMap)?;
+    let program = parse_data_byte(Some(program), ParseError::BadLaneMap)?;
+    Ok(LaneMap {
+        lane,
+        channel,
+        program,
+    })
+}
+
+/// Parses a pitch as a MIDI number or a note name such as `C4`, `D#5` or `Eb3`.
+pub fn parse_pitch(s: &str, middle_c: MiddleC) -> Result<Pitch, ParseError> {
+    use ParseError::BadPitch;
+    if let Ok(pitch) = s.parse() {
+        return Ok(pitch);
+    }
+    let mut chars = s.chars();
+    let letter = match chars.next().ok_or(BadPitch)?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return Err(BadPitch),
+    };
+    let rest = chars.as_str();
+    let (accidental, octave_str) = match rest.strip_prefix('#') {
+        Some(octave_str) => (1, octave_str),
+        None => match rest.strip_prefix('b') {
+            Some(octave_str) => (-1, octave_str),
+            None => (0, rest),
+        },
+    };
+    let octave: i32 = octave_str.parse().map_err(|_| BadPitch)?;
+    // middle C, 60, starts the octave `middle_c` names
+    let first_octave = match middle_c {
+        MiddleC::C4 => -1,
+        MiddleC::C3 => -2,
+    };
+    let pitch = (octave - first_octave) * 12 + letter + accidental;
+    Pitch::try_from(pitch).map_err(|_| BadPitch)
+}
+
+/// Parses a metronome sound as `PITCH[:VELOCITY]`, velocity 127 when left out.
+pub fn parse_click(s: &str, middle_c: MiddleC) -> Result<Click, ParseError> {
+    use ParseError::*;
+    let (pitch_str, velocity_str) = match s.split_once(':') {
+        Some((pitch_str, velocity_str)) => (pitch_str, Some(velocity_str)),
+        None => (s, None),
+    };
+    let pitch = Some(parse_pitch(pitch_str, middle_c)?)
+        .filter(|&pitch| pitch <= 127)
+        .ok_or(BadPitch)?;
+    let velocity = match velocity_str {
+        Some(velocity_str) => velocity_str
+            .parse()
+            .ok()
+            .filter(|velocity| (1..=127).contains(velocity))
+            .ok_or(BadVelocity)?,
+        None => DEFAULT_VELOCITY,
+    };
+    Ok(Click { pitch, velocity })
+}
+
+/// Parses a file of reference beat times, sorted and without repeats.
+pub fn parse_grid(s: &str) -> Result<Vec<Key>, ParseError> {
+    let mut grid = tokenize(s)
+        .map(|token| token.parse().map_err(|_| ParseError::BadGrid))
+        .collect::<Result<Vec<Key>, _>>()?;
+    if grid.is_empty() {
+        return Err(ParseError::BadGrid);
+    }
+    grid.sort_unstable();
+    grid.dedup();
+    Ok(grid)
+}
+
+/// Parses a tempo file: a single bpm, or pairs of `MS BPM` where each tempo holds from its time
+/// on.
+pub fn parse_tempo_file(s: &str) -> Result<Vec<(Key, Bpm)>, ParseError> {
+    use ParseError::BadTempoFile;
+    let bpm = |s: &str| {
+        s.parse()
+            .ok()
+            .filter(|bpm| (MIN_BPM..=MAX_BPM).contains(bpm))
+            .ok_or(BadTempoFile)
+    };
+    let tokens: Vec<&str> = tokenize(s).collect();
+    let mut map = match tokens[..] {
+        [] => return Err(BadTempoFile),
+        [only] => vec![(0, bpm(only)?)],
+        _ if tokens.len().is_multiple_of(2) => tokens
+            .chunks(2)
+            .map(|pair| Ok((parse_time(Some(pair[0]), BadTempoFile)?, bpm(pair[1])?)))
+            .collect::<Result<Vec<_>, ParseError>>()?,
+        _ => return Err(BadTempoFile),
+    };
+    // a later line for the same time overrides an earlier one
+    sort_latest(&mut map);
+    Ok(map)
+}
+
+/// Sorts `entries` by time, keeping only the last one given for each time.
+fn sort_latest<T: Ord + Copy, V: Copy>(entries: &mut Vec<(T, V)>) {
+    entries.sort_by_key(|&(at, _)| at);
+    entries.dedup_by(|later, earlier| {
+        let same = later.0 == earlier.0;
+        if same {
+            *earlier = *later;
+        }
+        same
+    });
+}
+
+/// Lays out beat times from zero to at least `end` by a tempo map, the first tempo holding
+/// until the first change.
+fn tempo_map_grid(map: &[(Key, Bpm)], end: Key) -> Vec<Key> {
+    let mut grid = Vec::new();
+    let mut time: f64 = 0.0;
+    let mut i = 0;
+    loop {
+        grid.push(time.round() as Key);
+        if time >= end as f64 {
+            return grid;
+        }
+        while map.get(i + 1).is_some_and(|&(at, _)| at as f64 <= time) {
+            i += 1;
+        }
+        time += 60_000.0 / map[i].1 as f64;
+    }
+}
+
+fn parse_time_signature(s: &str) -> Result<TimeSignature, ParseError> {
+    use ParseError::*;
+    let (numerator, denominator) = s.split_once('/').ok_or(BadTimeSignature)?;
+    let numerator: u8 = numerator.parse().map_err(|_| BadTimeSignature)?;
+    let denominator: u8 = denominator.parse().map_err(|_| BadTimeSignature)?;
+    if numerator == 0 || !denominator.is_power_of_two() || denominator > 64 {
+        return Err(BadTimeSignature);
+    }
+    Ok(TimeSignature {
+        numerator,
+        denominator,
+    })
+}
+
+/// Whether a time `below` past step `earlier` and `above` short of the next one snaps to the
+/// next one.
+fn later(below: Key, above: Key, earlier: Key, tie_break: TieBreak) -> bool {
+    match below.cmp(&above) {
+        Ordering::Less => false,
+        Ordering::Greater => true,
+        Ordering::Equal => match tie_break {
+            TieBreak::Down => false,
+            TieBreak::Up => true,
+            TieBreak::Even => earlier % 2 != 0,
+        },
+    }
+}
+
+/// Rounds `key` to the nearest step of `division` per beat, settling a tie by `tie_break`.
+fn snap(key: Key, tempo: Tempo, division: u32, tie_break: TieBreak) -> u64 {
+    // in units of 1/division µs, so steps that are not whole milliseconds stay exact
+    let key = key * 1000 * division as Key;
+    let beat = tempo as Key;
+    let ans_0 = key / beat;
+    let ans_1 = key / beat + 1;
+    let ans = if later(key - ans_0 * beat, ans_1 * beat - key, ans_0, tie_break) {
+        ans_1
+    } else {
+        ans_0
+    };
+    ans.max(0) as u64
+}
+
+/// Sums the distance in ms from each note to the step it snaps to.
+pub fn snap_error(input: &Input, division: u32) -> f64 {
+    let beat_ms = timing::beat_ms(input.tempo);
+    input
+        .notes
+        .iter()
+        .map(|note| {
+            // both steps of a tie are as far away
+            let step = snap(note.key, input.tempo, division, TieBreak::Down) as f64;
+            (note.key as f64 - step * beat_ms / division as f64).abs()
+        })
+        .sum()
+}
+
+/// Picks the division with the least snap error, the coarsest of those on a tie.
+pub fn best_division(input: &Input, divisions: &[u32]) -> u32 {
+    let mut best = (divisions[0], snap_error(input, divisions[0]));
+    for &division in &divisions[1..] {
+        let error = snap_error(input, division);
+        if error < best.1 {
+            best = (division, error);
+        }
+    }
+    best.0
+}
+
+/// Keeps the part of a chart from `from` to `to`, in ms of its own time, and moves it to start
+/// at zero when there is a `from`. A note without a written duration is kept when it starts in
+/// the range, and one with a duration crossing either end is cut or left out by `clip`.
+fn excerpt(mut input: Input, from: Option<Key>, to: Option<Key>, clip: Clip) -> Input {
+    let start = from.map_or(0, |from| from.saturating_add(input.shift).max(0));
+    let stop = to.map_or(Key::MAX, |to| to.saturating_add(input.shift));
+    input.notes = input
+        .notes
+        .into_iter()
+        .filter_map(|mut note| {
+            let end = note.key + note.duration.unwrap_or(0);
+            if note.key >= stop || end <= start && note.key < start {
+                return None;
+            }
+            if note.key < start || end > stop {
+                if clip == Clip::Drop {
+                    return None;
+                }
+                note.key = note.key.max(start);
+                note.duration = Some(end.min(stop) - note.key);
+            }
+            Some(note)
+        })
+        .collect();
+    input
+        .events
+        .retain(|event| (start..stop).contains(&event.key));
+    // the signature in force at the start carries over to it, a later directive on the same
+    // time overriding an earlier one
+    input.time_signatures.sort_by_key(|&(at, _)| at);
+    let mut time_signatures = Vec::new();
+    for &(at, signature) in &input.time_signatures {
+        if at <= start {
+            time_signatures = vec![(start, signature)];
+        } else if at < stop {
+            time_signatures.push((at, signature));
+        }
+    }
+    input.time_signatures = time_signatures;
+    input.end = input
+        .end
+        .filter(|&end| end > start)
+        .map(|end| end.min(stop));
+    input.shift_by(-start);
+    input
+}
+
+/// Raises `division` until the beat of every time signature is a whole number of steps, so the
+/// eighths of a 6/8 still have steps of their own on a grid of quarters.
+fn signature_division(mut division: u32, time_signatures: &[(Key, TimeSignature)]) -> u32 {
+    for &(_, signature) in time_signatures {
+        // denominators are powers of two, so doubling reaches a multiple
+        let per_quarter = (signature.denominator as u32 / 4).max(1);
+        while !division.is_multiple_of(per_quarter) {
+            division *= 2;
+        }
+    }
+    division
+}
+
+/// Finds the index of the reference time nearest to `key`, settling a tie by `tie_break`.
+fn snap_to_grid(key: Key, grid: &[Key], tie_break: TieBreak) -> u64 {
+    let i = grid.partition_point(|&time| time < key);
+    if i == 0 {
+        0
+    } else if i == grid.len() {
+        i as u64 - 1
+    } else if later(key - grid[i - 1], grid[i] - key, i as Key - 1, tie_break) {
+        i as u64
+    } else {
+        i as u64 - 1
+    }
+}
+
+pub fn solve(input: Input, options: &SolveOptions) -> Output {
+    let input = match (options.from, options.to) {
+        (None, None) => input,
+        (from, to) => excerpt(input, from, to, options.clip),
+    };
+    let tempo = input.tempo;
+    let shift = input.shift;
+    // a tempo map with changes lays out reference times of its own
+    let end = input.notes.iter().map(|note| note.key - shift).max();
+    let map_grid = options
+        .tempo_map
+        .as_deref()
+        .filter(|map| map.len() > 1)
+        .map(|map| tempo_map_grid(map, end.unwrap_or(0)));
+    let grid = options.grid.as_ref().or(map_grid.as_ref());
+    // reference times are whole beats
+    let division = match grid {
+        Some(_) => 1,
+        None => signature_division(options.division, &input.time_signatures),
+    };
+    // each signature's stretch snaps only as finely as it needs, in steps of
+    // the division the whole chart shares
+    let mut regions: Vec<(Key, u32)> = input
+        .time_signatures
+        .iter()
+        .map(|&change| (change.0, signature_division(options.division, &[change])))
+        .collect();
+    regions.sort_by_key(|&(at, _)| at);
+    let snap_in = |key: Key, ms: Key| {
+        let step = regions
+            .iter()
+            .rev()
+            .find(|&&(at, _)| at <= key)
+            .map_or(options.division, |&(_, step)| step);
+        snap(ms, tempo, step, options.tie_break) * (division / step) as u64
+    };
+    // the grid is in the chart's own time, before any pre-roll shift
+    let snap_key = |key| match grid {
+        Some(grid) => snap_to_grid(key - shift, grid, options.tie_break),
+        None => snap_in(key, key),
+    };
+    let mut notes: Vec<Hit> = input
+        .notes
+        .into_iter()
+        .map(|note| Hit {
+            beat: snap_key(note.key),
+            key: note.key,
+            exact: match options.quantize {
+                Quantize::Duration => Some(note.key),
+                Quantize::Onset | Quantize::Both => None,
+            },
+            // reference times have no regular length to snap a duration to
+            duration: note.duration.map(|ms| match (options.quantize, grid) {
+                (Quantize::Onset, _) | (_, Some(_)) => Hold::Ms(ms),
+                (Quantize::Both | Quantize::Duration, None) => {
+                    Hold::Steps(snap_in(note.key, ms).max(1))
+                }
+            }),
+            lane: note.lane,
+            pitch: note.pitch,
+            velocity: note.velocity,
+            channel: note.channel,
+        })
+        .collect();
+    let identity = |hit: &Hit| match options.dedup_by {
+        DedupBy::Time => (hit.beat, hit.channel, hit.lane, None),
+        DedupBy::Note => (hit.beat, hit.channel, hit.lane, Some(hit.pitch)),
+    };
+    // the sort is stable, so duplicates stay in chart order
+    notes.sort_by_key(identity);
+    if options.spread {
+        // each duplicate takes the next step it is not a duplicate on
+        let mut taken = HashSet::new();
+        for hit in &mut notes {
+            while !taken.insert(identity(hit)) {
+                hit.beat += 1;
+            }
+        }
+        notes.sort_by_key(|hit| (hit.beat, hit.channel, hit.lane, hit.pitch));
+    }
+    let mut merged: Vec<(u64, u32)> = Vec::new();
+    notes.dedup_by(|later, kept| {
+        if identity(later) != identity(kept) {
+            return false;
+        }
+        match merged.last_mut() {
+            Some((beat, count)) if *beat == kept.beat => *count += 1,
+            _ => merged.push((kept.beat, 1)),
+        }
+        if options.dedup_keep == DedupKeep::Loudest && later.velocity > kept.velocity {
+            *kept = *later;
+        }
+        true
+    });
+    if let Some(limit) = options.collapse_rolls {
+        let roll = |hit: &Hit| (hit.beat / division as u64, hit.channel, hit.pitch);
+        let mut counts = HashMap::new();
+        for hit in &notes {
+            *counts.entry(roll(hit)).or_insert(0) += 1;
+        }
+        // notes are in order, so the first of a roll is the one kept
+        let mut kept = HashSet::new();
+        notes.retain(|hit| counts[&roll(hit)] <= limit || kept.insert(roll(hit)));
+    }
+    let mut time_signatures: Vec<(u64, TimeSignature)> = input
+        .time_signatures
+        .into_iter()
+        .map(|(at, signature)| (snap_key(at), signature))
+        .collect();
+    // a later directive on the same beat overrides an earlier one
+    sort_latest(&mut time_signatures);
+    if time_signatures.first().map(|&(beat, _)| beat) != Some(0) {
+        time_signatures.insert(0, (0, TimeSignature::COMMON));
+    }
+    let mut events = input.events;
+    events.sort_by_key(|event| event.key);
+    // each gap between reference times is the length of its beat
+    let mut tempos: Vec<(u64, Tempo)> = match grid {
+        Some(grid) if options.grid_tempo || map_grid.is_some() => grid
+            .windows(2)
+            .enumerate()
+            .map(|(beat, taps)| {
+                let us = ((taps[1] - taps[0]) * 1000).clamp(MIN_TEMPO as Key, MAX_TEMPO as Key);
+                (beat as u64, us as Tempo)
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    tempos.dedup_by_key(|&mut (_, tempo)| tempo);
+    let (tempo, tempo_changes) = match tempos.split_first() {
+        Some((&(_, first), rest)) => (first, rest.to_vec()),
+        None => (tempo, Vec::new()),
+    };
+    Output {
+        tempo,
+        tempo_changes,
+        metadata: input.metadata,
+        division,
+        notes,
+        time_signatures,
+        events,
+        lfos: input.lfos,
+        merged,
+        grid: grid.map(|grid| grid.iter().map(|time| time + shift).collect()),
+        end: input.end.map(snap_key),
+    }
+}
+
+/// Parses, solves and builds one chart, with no filesystem access.
+pub fn convert(input: &str, opts: &Opts) -> Result<Vec<u8>, Error> {
+    let input = parse(input, &opts.parse)?;
+    let output = solve(input, &opts.solve);
+    Ok(build(output, &opts.build)?.into_vec())
+}
+
+pub struct Length {
+    pub seconds: f64,
+    pub bars: f64,
+}
+
+/// Measures a chart up to its last beat or its end, using the time signature in force over each
+/// stretch.
+pub fn length(output: &Output) -> Length {
+    let end = output.notes.last().map_or(0, |hit| hit.beat);
+    let end = end.max(output.end.unwrap_or(0));
+    let division = output.division as f64;
+    let mut seconds = 0.0;
+    let (mut from, mut tempo) = (0, output.tempo);
+    let changes = output
+        .tempo_changes
+        .iter()
+        .take_while(|&&(beat, _)| beat < end);
+    for &(beat, next) in changes.chain([&(end, output.tempo)]) {
+        seconds += (beat - from) as f64 / division * tempo as f64 / 1_000_000.0;
+        (from, tempo) = (beat, next);
+    }
+    let mut bars = 0.0;
+    for (i, &(beat, signature)) in output.time_signatures.iter().enumerate() {
+        let next = output
+            .time_signatures
+            .get(i + 1)
+            .map_or(end, |&(next, _)| next.min(end));
+        if next <= beat {
+            break;
+        }
+        // beats are quarter notes
+        let beats_per_bar = signature.numerator as f64 * 4.0 / signature.denominator as f64;
+        bars += (next - beat) as f64 / division / beats_per_bar;
+    }
+    Length { seconds, bars }
+}
+
+pub fn build(output: Output, options: &BuildOptions) -> Result<Box<[u8]>, OutputError> {
+    use TrackEventKind::*;
+    let ppq = 480;
+    let tempo = output.tempo;
+    let format = Format::Parallel;
+    let timing = Timing::Metrical(u15::new(ppq));
+    let header = Header::new(format, timing);
+    let lead_in = timing::ms_to_ticks(options.lead_in as u64, tempo, ppq);
+    let ticks = |beat: u64| timing::steps_to_ticks(beat, output.division, ppq) + lead_in;
+    let changes = output.tempo_changes.iter();
+    let tempo_map = TempoMap::new(
+        tempo,
+        changes.map(|&(beat, tempo)| (ticks(beat), tempo)),
+        ppq,
+    );
+    // times in ms count from the end of the lead-in, through the tempo changes
+    let ms_ticks = |ms: u64| tempo_map.ticks((options.lead_in as u64 + ms) * 1000);
+    // the tail rings on at the last tempo
+    let tail = timing::ms_to_ticks(options.tail as u64, tempo_map.last_tempo(), ppq);
+    // a declared end holds the tracks open past their last event
+    let chart_end = output.end.map_or(0, &ticks);
+    let track0 = {
+        let mut events = Vec::new();
+        if options.gm_reset {
+            // F0 7E 7F 09 01 F7, midly adds the leading F0
+            events.push((0, SysEx(&[0x7E, 0x7F, 0x09, 0x01, 0xF7])));
+        }
+        let title = output.metadata.title.as_deref().unwrap_or_default();
+        if !title.is_empty() || !options.compact {
+            events.push((0, Meta(MetaMessage::TrackName(title.as_bytes()))));
+        }
+        if let Some(artist) = &output.metadata.artist {
+            events.push((0, Meta(MetaMessage::Text(artist.as_bytes()))));
+        }
+        events.extend([
+            (0, Meta(time_signature_meta(output.time_signatures[0].1))),
+            (0, Meta(MetaMessage::Tempo(u24::new(tempo)))),
+        ]);
+        for &(beat, signature) in &output.time_signatures[1..] {
+            events.push((ticks(beat), Meta(time_signature_meta(signature))));
+        }
+        for &(beat, tempo) in &output.tempo_changes {
+            let tempo = Meta(MetaMessage::Tempo(u24::new(tempo)));
+            events.push((ticks(beat), tempo));
+        }
+        events.sort_by_key(|&(tick, _)| tick);
+        into_track(events, 0, 0, options.long_gap)?
+    };
+    check_lane_map(&options.lane_map, &output.notes, options.share_channels)?;
+    let lane_channel = |hit: &Hit| {
+        let map = options.lane_map.iter().find(|map| map.lane == hit.lane);
+        map.map_or(hit.channel, |map| map.channel)
+    };
+    let notes = output
+        .notes
+        .iter()
+        .map(|&hit| {
+            let pitch = fit_pitch(hit.pitch, options.out_of_range)?;
+            let velocity = curve_velocity(hit.velocity, options.velocity_curve);
+            Ok(Hit {
+                pitch,
+                velocity,
+                channel: lane_channel(&hit),
+                ..hit
+            })
+        })
+        .collect::<Result<Vec<_>, OutputError>>()?;
+    let notes = match options.normalize_velocity {
+        Some(peak) => normalize_velocities(notes, peak),
+        None => notes,
+    };
+    // mapped channels start on their program
+    let mut events: Vec<Event> = options
+        .lane_map
+        .iter()
+        .map(|map| Event {
+            key: 0,
+            channel: map.channel,
+            kind: EventKind::Program(map.program),
+        })
+        .collect();
+    events.dedup();
+    events.extend_from_slice(&output.events);
+    events.sort_by_key(|event| event.key);
+    // (on, off, hit) for every note, each channel scheduled on its own
+    let mut scheduled = Vec::with_capacity(notes.len());
+    let mut channels: Vec<u8> = notes.iter().map(|hit| hit.channel).collect();
+    channels.sort_unstable();
+    channels.dedup();
+    for channel in channels {
+        let layer: Vec<Hit> = notes
+            .iter()
+            .filter(|hit| hit.channel == channel)
+            .copied()
+            .collect();
+        let at_ms = |ms: Key| ms_ticks(ms as u64);
+        let hold_ticks = |hold: Hold, beat: u64| match hold {
+            Hold::Ms(ms) => {
+                let on = tempo_map.us(ticks(beat));
+                tempo_map.ticks(on + ms as u64 * 1000) - ticks(beat)
+            }
+            Hold::Steps(steps) => ticks(beat + steps) - ticks(beat),
+        };
+        let timing = Schedule {
+            ticks,
+            at_ms,
+            hold_ticks,
+            min_duration_ticks: options.min_duration_ticks as u64,
+        };
+        scheduled.extend(resolve_overlaps(schedule(&layer, &timing), options.overlap));
+    }
+    // one note track per channel, or per channel and pitch when splitting
+    let track_of = |hit: &Hit| (hit.channel, options.split_by_pitch.then_some(hit.pitch));
+    let mut groups: Vec<(u8, Option<Pitch>)> =
+        scheduled.iter().map(|(_, _, hit)| track_of(hit)).collect();
+    groups.sort_unstable();
+    groups.dedup();
+    // a channel with only events still gets a track for them
+    let event_channels = events.iter().map(|event| event.channel);
+    for channel in event_channels.chain(output.lfos.iter().map(|lfo| lfo.channel)) {
+        if !groups.iter().any(|&(group, _)| group == channel) {
+            groups.push((channel, None));
+        }
+    }
+    groups.sort_unstable();
+    // a metronome is enough of a track on its own
+    if groups.is_empty() && !options.split_by_pitch && options.metronome.is_none() {
+        groups.push((0, None));
+    }
+    let names: Vec<Option<String>> = groups
+        .iter()
+        .map(|&(_, pitch)| pitch.map(pitch_name))
+        .collect();
+    // LFOs sweep from the start to where the last note or the chart ends
+    let last_off = scheduled.iter().map(|&(_, off, _)| off).max().unwrap_or(0);
+    let lfo_end = last_off.max(chart_end);
+    let note_tracks = groups.iter().enumerate().map(|(i, &group)| {
+        let notes: Vec<&(u64, u64, Hit)> = scheduled
+            .iter()
+            .filter(|(_, _, hit)| track_of(hit) == group)
+            .collect();
+        // events go on the first track of their channel
+        let channel = group.0;
+        let first = i == 0 || groups[i - 1].0 != channel;
+        let channel_events: Vec<&Event> = if first {
+            events.iter().filter(|e| e.channel == channel).collect()
+        } else {
+            Vec::new()
+        };
+        let lfos = output
+            .lfos
+            .iter()
+            .filter(|lfo| first && lfo.channel == channel);
+        // a name, an on and an off per note, and the channel events
+        let mut events = Vec::with_capacity(1 + 2 * notes.len() + channel_events.len());
+        if let Some(name) = &names[i] {
+            events.push((0, Meta(MetaMessage::TrackName(name.as_bytes()))));
+        }
+        push_note_events(&mut events, notes, options);
+        for event in channel_events {
+            events.push((ms_ticks(event.key as u64), channel_event(event)));
+        }
+        for lfo in lfos {
+            events.extend(lfo_events(
+                lfo,
+                lead_in,
+                lfo_end,
+                ppq,
+                options.lfo_resolution,
+            ));
+        }
+        sort_events(&mut events);
+        into_track(events, tail, chart_end, options.long_gap)
+    });
+    let metronome_track = options.metronome.map(|metronome| {
+        // up to where the last note or the chart ends, or its own length
+        let end = lfo_end.max(ms_ticks(metronome.length as u64));
+        let regions: Vec<(u64, TimeSignature)> = output
+            .time_signatures
+            .iter()
+            .map(|&(beat, signature)| (ticks(beat), signature))
+            .collect();
+        let clicks = clicks(&regions, end, ppq, metronome);
+        let mut events = Vec::with_capacity(1 + 2 * clicks.len());
+        events.push((0, Meta(MetaMessage::TrackName(b"Metronome"))));
+        push_note_events(&mut events, &clicks, options);
+        sort_events(&mut events);
+        into_track(events, tail, chart_end, options.long_gap)
+    });
+    let mut smf = Smf::new(header);
+    smf.tracks = Vec::with_capacity(2 + groups.len());
+    smf.tracks.push(track0);
+    for track in note_tracks {
+        smf.tracks.push(track?);
+    }
+    smf.tracks.extend(metronome_track.transpose()?);
+    // a header, then a chunk header per track and rarely more than 4 bytes per event
+    let estimate = 14 + smf.tracks.iter().map(|t| 8 + 4 * t.len()).sum::<usize>();
+    let mut binary = Vec::with_capacity(estimate);
+    smf.write_std(&mut binary)?;
+    Ok(binary.into_boxed_slice())
+}
+
+/// A channel event of the built file at its time in ms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TimedEvent {
+    pub time_ms: u64,
+    /// One of note_on, note_off, poly_aftertouch, controller, program, aftertouch and
+    /// pitch_bend.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub kind: &'static str,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub pitch: Option<Pitch>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub velocity: Option<Velocity>,
+    pub channel: u8,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub controller: Option<u8>,
+    /// The controller value, program, or 14-bit pitch bend.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub value: Option<u16>,
+}
+
+/// Lists the channel events `build` would write, across all tracks and in time order, for
+/// players that schedule their own playback.
+///
+/// A zero-velocity NoteOn is listed as a note_off.
+pub fn timed_events(
+    output: Output,
+    options: &BuildOptions,
+) -> Result<Vec<TimedEvent>, OutputError> {
+    let bytes = build(output, options)?;
+    let smf = Smf::parse(&bytes).expect("build writes valid MIDI");
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int(),
+        Timing::Timecode(..) => unreachable!("build writes metrical timing"),
+    };
+    let mut all = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            all.push((tick, event.kind));
+        }
+    }
+    all.sort_by_key(|&(tick, _)| tick);
+    // the time of the latest tempo change, to count on from
+    let (mut tempo, mut since_tick, mut since_ms) = (500_000, 0, 0);
+    let mut timed = Vec::new();
+    for (tick, kind) in all {
+        let time_ms = since_ms + timing::ticks_to_ms(tick - since_tick, tempo, ppq);
+        let (channel, message) = match kind {
+            TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+            TrackEventKind::Meta(MetaMessage::Tempo(new)) => {
+                (tempo, since_tick, since_ms) = (new.as_int(), tick, time_ms);
+                continue;
+            }
+            _ => continue,
+        };
+        let event = |kind| TimedEvent {
+            time_ms,
+            kind,
+            pitch: None,
+            velocity: None,
+            channel,
+            controller: None,
+            value: None,
+        };
+        timed.push(match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => TimedEvent {
+                pitch: Some(key.as_int()),
+                velocity: Some(vel.as_int()),
+                ..event("note_on")
+            },
+            MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => TimedEvent {
+                pitch: Some(key.as_int()),
+                velocity: Some(vel.as_int()),
+                ..event("note_off")
+            },
+            MidiMessage::Aftertouch { key, vel } => TimedEvent {
+                pitch: Some(key.as_int()),
+                velocity: Some(vel.as_int()),
+                ..event("poly_aftertouch")
+            },
+            MidiMessage::Controller { controller, value } => TimedEvent {
+                controller: Some(controller.as_int()),
+                value: Some(value.as_int() as u16),
+                ..event("controller")
+            },
+            MidiMessage::ProgramChange { program } => TimedEvent {
+                value: Some(program.as_int() as u16),
+                ..event("program")
+            },
+            MidiMessage::ChannelAftertouch { vel } => TimedEvent {
+                velocity: Some(vel.as_int()),
+                ..event("aftertouch")
+            },
+            MidiMessage::PitchBend { bend } => TimedEvent {
+                value: Some(bend.0.as_int()),
+                ..event("pitch_bend")
+            },
+        });
+    }
+    Ok(timed)
+}
+
+/// Conversions `schedule` needs from the output's time units to ticks.
+struct Schedule<T, A, H> {
+    /// Tick of a grid step.
+    ticks: T,
+    /// Tick of a time in ms.
+    at_ms: A,
+    /// Length in ticks of a hold starting on a grid step.
+    hold_ticks: H,
+    min_duration_ticks: u64,
+}
+
+/// Times the notes of one channel as (on, off, hit), in order.
+fn schedule<T, A, H>(notes: &[Hit], timing: &Schedule<T, A, H>) -> Vec<(u64, u64, Hit)>
+where
+    T: Fn(u64) -> u64,
+    A: Fn(Key) -> u64,
+    H: Fn(Hold, u64) -> u64,
+{
+    let mut scheduled = Vec::with_capacity(notes.len());
+    let chords: Vec<&[Hit]> = notes.chunk_by(|a, b| a.beat == b.beat).collect();
+    // the earliest tick the next chord may start on
+    let mut floor = 0;
+    for (i, chord) in chords.iter().enumerate() {
+        // a chord rings until the next one starts, the last for one step
+        let beat = chord[0].beat;
+        let end = chords.get(i + 1).map_or(beat + 1, |next| next[0].beat);
+        let mut next_floor = floor;
+        for &hit in chord.iter() {
+            let on = match hit.exact {
+                Some(ms) => (timing.at_ms)(ms),
+                None => (timing.ticks)(beat),
+            }
+            .max(floor);
+            // written durations may overlap later notes, so only the others push them back
+            let off = match hit.duration {
+                Some(hold) => on + (timing.hold_ticks)(hold, beat),
+                None => {
+                    let off = (timing.ticks)(end).max(on + timing.min_duration_ticks);
+                    next_floor = next_floor.max(off);
+                    off
+                }
+            }
+            .max(on + timing.min_duration_ticks);
+            scheduled.push((on, off, hit));
+        }
+        floor = next_floor;
+    }
+    scheduled
+}
+
+/// Settles notes of one channel that start while the same pitch still sounds, by `policy`.
+///
+/// A note starting on the same tick as the one it overlaps always merges into it, since
+/// truncating would leave nothing of the first.
+fn resolve_overlaps(mut notes: Vec<(u64, u64, Hit)>, policy: Overlap) -> Vec<(u64, u64, Hit)> {
+    // the sort is stable, so of notes on one tick the first scheduled is the one merged into
+    notes.sort_by_key(|&(on, _, _)| on);
+    let mut resolved: Vec<(u64, u64, Hit)> = Vec::with_capacity(notes.len());
+    // the latest note of each pitch, as an index into resolved
+    let mut latest: HashMap<Pitch, usize> = HashMap::new();
+    for (on, mut off, hit) in notes {
+        if let Some(&i) = latest.get(&hit.pitch) {
+            let (sounding_on, sounding_off, _) = &mut resolved[i];
+            if *sounding_off > on {
+                let end = off.max(*sounding_off);
+                if policy == Overlap::Merge || *sounding_on == on {
+                    *sounding_off = end;
+                    continue;
+                }
+                *sounding_off = on;
+                if policy == Overlap::Retrigger {
+                    off = end;
+                }
+            }
+        }
+        latest.insert(hit.pitch, resolved.len());
+        resolved.push((on, off, hit));
+    }
+    resolved
+}
+
+/// Schedules a click on every beat of each time signature before `end`, accenting the first
+/// of each bar, given the tick each signature starts on.
+fn clicks(
+    regions: &[(u64, TimeSignature)],
+    end: u64,
+    ppq: u16,
+    sounds: Metronome,
+) -> Vec<(u64, u64, Hit)> {
+    let mut clicks = Vec::new();
+    for (i, &(start, signature)) in regions.iter().enumerate() {
+        let stop = regions.get(i + 1).map_or(end, |&(next, _)| next.min(end));
+        // beats of the signature are denominator notes, not quarters
+        let beat_ticks = (ppq as u64 * 4 / signature.denominator as u64).max(1);
+        let mut tick = start;
+        let mut beat: u64 = 0;
+        while tick < stop {
+            let click = match beat % signature.numerator as u64 {
+                0 => sounds.accent,
+                _ => sounds.beat,
+            };
+            let hit = Hit {
+                beat: 0,
+                key: 0,
+                exact: None,
+                duration: None,
+                lane: 0,
+                pitch: click.pitch,
+                velocity: click.velocity,
+                channel: PERCUSSION_CHANNEL,
+            };
+            clicks.push((tick, tick + (beat_ticks / 2).max(1), hit));
+            tick += beat_ticks;
+            beat += 1;
+        }
+    }
+    clicks
+}
+
+/// Adds NoteOn/NoteOff events at absolute ticks for scheduled notes, leaving them unsorted.
+fn push_note_events<'a>(
+    events: &mut Vec<(u64, TrackEventKind)>,
+    notes: impl IntoIterator<Item = &'a (u64, u64, Hit)>,
+    options: &BuildOptions,
+) {
+    let style = if options.compact {
+        NoteOffStyle::ZeroVel
+    } else {
+        options.note_off_style
+    };
+    for &(on, off, hit) in notes {
+        let channel = u4::new(hit.channel);
+        let key = u7::new(hit.pitch);
+        let vel = u7::new(hit.velocity);
+        events.push((
+            on,
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { key, vel },
+            },
+        ));
+        events.push((
+            off,
+            TrackEventKind::Midi {
+                channel,
+                message: note_off(key, style),
+            },
+        ));
+    }
+}
+
+/// Orders events by tick, with notes ending on a tick before notes starting on it.
+fn sort_events(events: &mut [(u64, TrackEventKind)]) {
+    events.sort_by_key(|&(tick, kind)| (tick, is_note_on(kind)));
+}
+
+/// Rejects a lane mapped twice, and a channel shared by lanes unless `share` allows it and they
+/// agree on the program. An unmapped lane keeps its chart's channel, so a mapping onto that
+/// channel is shared with it too.
+fn check_lane_map(lane_map: &[LaneMap], notes: &[Hit], share: bool) -> Result<(), OutputError> {
+    for (i, map) in lane_map.iter().enumerate() {
+        for earlier in &lane_map[..i] {
+            if earlier.lane == map.lane {
+                return Err(OutputError::LaneMappedTwice(map.lane));
+            }
+            if earlier.channel == map.channel && !(share && earlier.program == map.program) {
+                return Err(OutputError::SharedChannel(map.channel));
+            }
+        }
+    }
+    if share {
+        return Ok(());
+    }
+    let unmapped = notes
+        .iter()
+        .filter(|hit| lane_map.iter().all(|map| map.lane != hit.lane));
+    for hit in unmapped {
+        if lane_map.iter().any(|map| map.channel == hit.channel) {
+            return Err(OutputError::SharedChannel(hit.channel));
+        }
+    }
+    Ok(())
+}
+
+/// Brings a pitch into the MIDI range of 0 to 127 according to `policy`.
+fn fit_pitch(pitch: Pitch, policy: OutOfRange) -> Result<Pitch, OutputError> {
+    if pitch <= 127 {
+        return Ok(pitch);
+    }
+    match policy {
+        OutOfRange::Error => Err(OutputError::PitchOutOfRange(pitch)),
+        OutOfRange::Clamp => Ok(127),
+        OutOfRange::OctaveWrap => {
+            let mut pitch = pitch;
+            while pitch > 127 {
+                pitch -= 12;
+            }
+            Ok(pitch)
+        }
+    }
+}
+
+/// Remaps a velocity through `curve`, keeping it at least 1 so the note still sounds.
+fn curve_velocity(velocity: Velocity, curve: VelocityCurve) -> Velocity {
+    let x = velocity as f64 / 127.0;
+    let y = match curve {
+        VelocityCurve::Linear => return velocity,
+        VelocityCurve::Exp => x * x,
+        VelocityCurve::Log => x.sqrt(),
+    };
+    ((y * 127.0).round() as Velocity).clamp(1, 127)
+}
+
+/// Scales velocities so the loudest becomes `peak`, keeping their proportions and keeping each
+/// at least 1 so the note still sounds. Notes that are all silent are left as they are.
+fn normalize_velocities(mut notes: Vec<Hit>, peak: Velocity) -> Vec<Hit> {
+    let loudest = notes.iter().map(|hit| hit.velocity).max().unwrap_or(0);
+    if loudest == 0 {
+        return notes;
+    }
+    let scale = peak as f64 / loudest as f64;
+    for hit in &mut notes {
+        hit.velocity = ((hit.velocity as f64 * scale).round() as Velocity).clamp(1, 127);
+    }
+    notes
+}
+
+/// Names a pitch after its General MIDI percussion sound, if it has one.
+fn pitch_name(pitch: Pitch) -> String {
+    const GM_DRUMS: [&str; 47] = [
+        "Acoustic Bass Drum",
+        "Bass Drum 1",
+        "Side Stick",
+        "Acoustic Snare",
+        "Hand Clap",
+        "Electric Snare",
+        "Low Floor Tom",
+        "Closed Hi-Hat",
+        "High Floor Tom",
+        "Pedal Hi-Hat",
+        "Low Tom",
+        "Open Hi-Hat",
+        "Low-Mid Tom",
+        "Hi-Mid Tom",
+        "Crash Cymbal 1",
+        "High Tom",
+        "Ride Cymbal 1",
+        "Chinese Cymbal",
+        "Ride Bell",
+        "Tambourine",
+        "Splash Cymbal",
+        "Cowbell",
+        "Crash Cymbal 2",
+        "Vibraslap",
+        "Ride Cymbal 2",
+        "Hi Bongo",
+        "Low Bongo",
+        "Mute Hi Conga",
+        "Open Hi Conga",
+        "Low Conga",
+        "High Timbale",
+        "Low Timbale",
+        "High Agogo",
+        "Low Agogo",
+        "Cabasa",
+        "Maracas",
+        "Short Whistle",
+        "Long Whistle",
+        "Short Guiro",
+        "Long Guiro",
+        "Claves",
+        "Hi Wood Block",
+        "Low Wood Block",
+        "Mute Cuica",
+        "Open Cuica",
+        "Mute Triangle",
+        "Open Triangle",
+    ];
+    match pitch.checked_sub(35).and_then(|i| GM_DRUMS.get(i as usize)) {
+        Some(name) => name.to_string(),
+        None => format!("Pitch {}", pitch),
+    }
+}
+
+/// Turns events at absolute ticks, in order, into a track ending `tail` ticks after the last of
+/// them or at `end`, whichever is later, with `long_gap` deciding what happens to a gap too long
+/// for one delta.
+fn into_track(
+    events: Vec<(u64, TrackEventKind)>,
+    tail: u64,
+    end: u64,
+    long_gap: LongGap,
+) -> Result<Vec<TrackEvent>, OutputError> {
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut push = |mut delta: u64, kind| {
+        let max = u28::max_value().as_int() as u64;
+        while delta > max {
+            if long_gap == LongGap::Error {
+                return Err(OutputError::GapTooLong(delta));
+            }
+            // an empty text event carries as much of the gap as a delta can hold
+            track.push(TrackEvent {
+                delta: u28::max_value(),
+                kind: TrackEventKind::Meta(MetaMessage::Text(b"")),
+            });
+            delta -= max;
+        }
+        track.push(TrackEvent {
+            delta: u28::new(delta as u32),
+            kind,
+        });
+        Ok(())
+    };
+    let mut last = 0;
+    for (tick, kind) in events {
+        push(tick - last, kind)?;
+        last = tick;
+    }
+    let end = (last + tail).max(end);
+    push(end - last, TrackEventKind::Meta(MetaMessage::EndOfTrack))?;
+    Ok(track)
+}
+
+/// Where each tempo of a file starts, in ticks and in microseconds, so times in ms land where
+/// the tempo changes put them.
+struct TempoMap {
+    ppq: u16,
+    /// Tick, microseconds from the start and tempo of each stretch, sorted.
+    stretches: Vec<(u64, u64, Tempo)>,
+}
+
+impl TempoMap {
+    /// Lays out `tempo` from tick 0 and then each change at its tick.
+    fn new(tempo: Tempo, changes: impl Iterator<Item = (u64, Tempo)>, ppq: u16) -> Self {
+        let mut stretches = vec![(0, 0, tempo)];
+        for (tick, next) in changes {
+            let (from, us, tempo) = stretches[stretches.len() - 1];
+            stretches.push((tick, us + (tick - from) * tempo as u64 / ppq as u64, next));
+        }
+        TempoMap { ppq, stretches }
+    }
+
+    fn last_tempo(&self) -> Tempo {
+        self.stretches[self.stretches.len() - 1].2
+    }
+
+    /// The tick nearest to `us` microseconds from the start.
+    fn ticks(&self, us: u64) -> u64 {
+        let i = self.stretches.partition_point(|&(_, start, _)| start <= us) - 1;
+        let (tick, start, tempo) = self.stretches[i];
+        let tempo = tempo as u64;
+        tick + ((us - start) * self.ppq as u64 + tempo / 2) / tempo
+    }
+
+    /// Microseconds from the start to `tick`.
+    fn us(&self, tick: u64) -> u64 {
+        let i = self.stretches.partition_point(|&(from, _, _)| from <= tick) - 1;
+        let (from, start, tempo) = self.stretches[i];
+        start + (tick - from) * tempo as u64 / self.ppq as u64
+    }
+}
+
+/// Samples an LFO `per_beat` times a beat from tick `start` up to `end`.
+fn lfo_events(
+    lfo: &Lfo,
+    start: u64,
+    end: u64,
+    ppq: u16,
+    per_beat: u32,
+) -> Vec<(u64, TrackEventKind<'static>)> {
+    let step = (ppq as u64 / per_beat.max(1) as u64).max(1);
+    (start..end)
+        .step_by(step as usize)
+        .map(|tick| {
+            let beats = (tick - start) as f64 / ppq as f64;
+            let swing = (beats / lfo.rate * std::f64::consts::TAU).sin();
+            let value = (64.0 + lfo.depth as f64 * swing).round() as u8;
+            let message = MidiMessage::Controller {
+                controller: u7::new(lfo.controller),
+                value: u7::new(value),
+            };
+            let channel = u4::new(lfo.channel);
+            (tick, TrackEventKind::Midi { channel, message })
+        })
+        .collect()
+}
+
+fn channel_event(event: &Event) -> TrackEventKind<'static> {
+    let message = match event.kind {
+        EventKind::PitchBend(value) => MidiMessage::PitchBend {
+            bend: PitchBend(u14::new(value)),
+        },
+        EventKind::Aftertouch(value) => MidiMessage::ChannelAftertouch {
+            vel: u7::new(value),
+        },
+        EventKind::PolyAftertouch { pitch, value } => MidiMessage::Aftertouch {
+            key: u7::new(pitch),
+            vel: u7::new(value),
+        },
+        EventKind::Controller { controller, value } => MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value),
+        },
+        EventKind::Program(program) => MidiMessage::ProgramChange {
+            program: u7::new(program),
+        },
+    };
+    TrackEventKind::Midi {
+        channel: u4::new(event.channel),
+        message,
+    }
+}
+
+fn is_note_on(kind: TrackEventKind) -> bool {
+    matches!(
+        kind,
+        TrackEventKind::Midi {
+            message: MidiMessage::NoteOn { vel, .. },
+            ..
+        } if vel > 0
+    )
+}
+
+fn note_off(key: u7, style: NoteOffStyle) -> MidiMessage {
+    let vel = u7::new(0);
+    match style {
+        NoteOffStyle::Off => MidiMessage::NoteOff { key, vel },
+        NoteOffStyle::ZeroVel => MidiMessage::NoteOn { key, vel },
+    }
+}
+
+fn time_signature_meta(signature: TimeSignature) -> MetaMessage<'static> {
+    let TimeSignature {
+        numerator,
+        denominator,
+    } = signature;
+    // one metronome click per denominator note, 24 MIDI clocks per quarter
+    let clocks_per_click = 96 / denominator.min(32);
+    MetaMessage::TimeSignature(
+        numerator,
+        denominator.trailing_zeros() as u8,
+        clocks_per_click,
+        8,
+    )
+}
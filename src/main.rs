@@ -1,178 +1,694 @@
-use clap::Parser;
-use midly::num::{u15, u24, u28, u4, u7};
-use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
-use std::fmt::Debug;
-use thiserror::Error;
+use clap::{ArgGroup, Parser, ValueEnum};
+use naive_rhythm::{
+    best_division, build, error_kinds, import, length, merge, parse, parse_click, parse_grid,
+    parse_lane_map, parse_tempo_file, solve, timed_events, timing, Bpm, BuildOptions, Click, Clip,
+    DedupBy, DedupKeep, ImportOptions, InputMode, Key, LaneMap, Length, LongGap, Metronome,
+    MiddleC, NoteOffStyle, Opts, OutOfRange, Output, Overlap, ParseOptions, Pitch, Place, Quantize,
+    Report, SolveOptions, Tempo, TieBreak, Velocity, VelocityCurve, AUTO_DIVISIONS, MAX_BPM,
+    MAX_KEY, MAX_TEMPO, MIN_BPM, MIN_TEMPO,
+};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// A standard MIDI file
+    Midi,
+    /// A JSON array of every channel event with its time in ms, for custom players
+    Events,
+    /// A metronome alone, for --seconds at the given tempo
+    Click,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ErrorFormat {
+    /// A line of text
+    Text,
+    /// A JSON object with the error's stage, kind, place in the chart and message, for wrappers
+    /// to read
+    Json,
+}
+
+/// A failure as --error-format json prints it.
+#[derive(Serialize)]
+struct Failure {
+    /// With --batch, the input that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<String>,
+    /// What was being done, such as "failed to parse the input".
+    stage: &'static str,
+    /// The error's variant, such as "BadPitch", or for a file error its kind, such as "NotFound".
+    kind: String,
+    /// For a parse error, the line and token of the chart it is on.
+    #[serde(flatten)]
+    place: Option<Place>,
+    message: String,
+}
+
+impl Failure {
+    fn new(stage: &'static str, e: &impl Report) -> Failure {
+        Failure {
+            input: None,
+            stage,
+            kind: e.kind(),
+            place: e.place().cloned(),
+            message: e.to_string(),
+        }
+    }
+}
+
+error_kinds! {
+    /// Arguments that clap cannot check on its own.
+    #[derive(thiserror::Error, Debug)]
+    enum UsageError {
+        #[error("--reverse takes a single --input")]
+        ReverseInputs,
+        #[error("--seconds needs --emit click or --metronome")]
+        SecondsWithoutClick,
+        #[error("an earlier input is already converted to {0}")]
+        SharedOutput(String),
+    }
+}
+
+/// Finds --error-format among the arguments as given, for the failures that stop clap reading
+/// them.
+fn raw_error_format() -> ErrorFormat {
+    let args: Vec<String> = std::env::args().collect();
+    let json = args.iter().any(|arg| arg == "--error-format=json")
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "--error-format" && pair[1] == "json");
+    if json {
+        ErrorFormat::Json
+    } else {
+        ErrorFormat::Text
+    }
+}
+
+trait OrFail<T> {
+    /// Unwraps a result, or reports the error after `stage` in `format` and exits.
+    fn or_fail(self, stage: &'static str, format: ErrorFormat) -> T;
+}
+
+impl<T, E: Report> OrFail<T> for Result<T, E> {
+    fn or_fail(self, stage: &'static str, format: ErrorFormat) -> T {
+        let e = match self {
+            Ok(value) => return value,
+            Err(e) => e,
+        };
+        if format == ErrorFormat::Text {
+            panic!("{}: {:?}", stage, e);
+        }
+        let failure = Failure::new(stage, &e);
+        eprintln!("{}", serde_json::to_string(&failure).unwrap());
+        std::process::exit(1);
+    }
+}
+
+/// A format that is not an --emit choice, with a line on what it is for --list-formats.
+struct Format {
+    name: &'static str,
+    description: &'static str,
+}
+
+/// What --input can read.
+const INPUT_FORMATS: [Format; 2] = [
+    Format {
+        name: "chart",
+        description: "A naive-rhythm chart of keys, with its header or with --no-magic",
+    },
+    Format {
+        name: "midi",
+        description: "A standard MIDI file, turned back into a chart with --reverse",
+    },
+];
+
+/// What --output can hold besides the --emit choices.
+const OUTPUT_FORMATS: [Format; 1] = [Format {
+    name: "chart",
+    description: "A naive-rhythm chart, from --reverse",
+}];
+
+/// Prints every input and output format, the --emit ones straight from their docs.
+fn list_formats() {
+    println!("input formats:");
+    for format in &INPUT_FORMATS {
+        println!("  {:8}{}", format.name, format.description);
+    }
+    println!("output formats:");
+    for emit in Emit::value_variants() {
+        let value = emit.to_possible_value().unwrap();
+        println!("  {:8}{}", value.get_name(), value.get_help().unwrap_or(""));
+    }
+    for format in &OUTPUT_FORMATS {
+        println!("  {:8}{}", format.name, format.description);
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
+#[clap(group(ArgGroup::new("tempo").args(&["bpm", "tempo-us", "bpm-file"])))]
 pub struct Args {
+    /// Input chart; repeat to merge several charts, each on its own channel and track
+    #[clap(short, long, required_unless_present_any = &["keys", "seconds", "list-formats"], conflicts_with = "keys")]
+    input: Vec<String>,
+    /// Comma-separated keys to use instead of an input file; needs a tempo option
+    #[clap(long, value_delimiter = ',', requires = "tempo")]
+    keys: Vec<String>,
+    /// Read input charts that have no magic or bpm header; needs a tempo option
+    #[clap(long, requires = "tempo")]
+    no_magic: bool,
+    /// Whether keys are times, or gaps since the previous note
+    #[clap(long, value_enum, default_value = "absolute")]
+    input_mode: InputMode,
+    /// Which octave middle C is in for note names such as C4 or D#5
+    #[clap(long, value_enum, default_value = "c4")]
+    middle_c: MiddleC,
+    #[clap(short, long, required_unless_present_any = &["duration", "batch", "list-formats"])]
+    output: Option<String>,
+    /// Convert each --input on its own into this directory, named after the input, instead of
+    /// merging them
+    #[clap(long, value_name = "DIR", conflicts_with_all = &["output", "keys", "reverse", "duration", "auto-division", "emit"])]
+    batch: Option<PathBuf>,
+    /// With --batch, how many inputs to convert at once
+    #[clap(long, value_name = "N", default_value_t = 1, requires = "batch", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// What to write to --output
+    #[clap(long, value_enum, default_value = "midi")]
+    emit: Emit,
+    /// Also write the solved notes, their snap errors and chart stats as JSON next to --output, as NAME.sidecar.json
+    #[clap(long, requires = "output")]
+    sidecar: bool,
+    /// Turn a MIDI file back into a chart; takes a single --input
+    #[clap(long, conflicts_with = "keys")]
+    reverse: bool,
+    /// With --reverse, name the gap before each note as a musical duration in a comment
+    #[clap(long, requires = "reverse")]
+    label_durations: bool,
+    /// Print the chart's length in seconds and bars
+    #[clap(long)]
+    duration: bool,
+    /// Length of the --emit click or --metronome track; needs a tempo option
+    #[clap(long, required_if_eq("emit", "click"), requires = "tempo", conflicts_with_all = &["input", "keys"])]
+    seconds: Option<f64>,
+    /// Override the chart's tempo in beats per minute
+    #[clap(long, value_parser = clap::value_parser!(u32).range(MIN_BPM as i64..=MAX_BPM as i64))]
+    bpm: Option<Bpm>,
+    /// Override the chart's tempo in microseconds per quarter note
+    #[clap(long, conflicts_with = "bpm", value_parser = clap::value_parser!(u32).range(MIN_TEMPO as i64..=MAX_TEMPO as i64))]
+    tempo_us: Option<Tempo>,
+    /// Override the chart's tempo from a file of a bpm, or of MS BPM pairs for a tempo map whose
+    /// beats notes snap to
+    #[clap(long, value_name = "PATH", conflicts_with_all = &["bpm", "tempo-us", "grid"])]
+    bpm_file: Option<String>,
+    /// How notes are ended in the output
+    #[clap(long, value_enum, default_value = "off")]
+    note_off_style: NoteOffStyle,
+    /// What to do when a note starts while the same pitch is still sounding
+    #[clap(long, value_enum, default_value = "truncate")]
+    overlap: Overlap,
+    /// How chart velocities map onto MIDI velocities
+    #[clap(long, value_enum, default_value = "linear")]
+    velocity_curve: VelocityCurve,
+    /// Scale velocities so the loudest note plays at PEAK
+    #[clap(long, value_name = "PEAK", value_parser = clap::value_parser!(u8).range(1..=127))]
+    normalize_velocity: Option<u8>,
+    /// What makes two notes on the same beat duplicates of each other
+    #[clap(long, value_enum, default_value = "note")]
+    dedup_by: DedupBy,
+    /// Which of several duplicate notes survives
+    #[clap(long, value_enum, default_value = "first")]
+    dedup_keep: DedupKeep,
+    /// File of reference beat times in ms to snap to, such as taps along with the song
+    #[clap(long)]
+    grid: Option<String>,
+    /// Write a tempo change on every beat of --grid, so playback follows the reference times
+    #[clap(long, requires = "grid")]
+    grid_tempo: bool,
+    /// Steps per beat to snap to
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=96))]
+    division: u32,
+    /// Pick the division that fits the chart best from 1, 2, 3, 4, 6 and 8, and print it
+    #[clap(long, conflicts_with_all = &["division", "grid"])]
+    auto_division: bool,
+    /// Which of a note's start and written duration snap to the grid
+    #[clap(long, value_enum, default_value = "onset")]
+    quantize: Quantize,
+    /// Which step a note exactly between two snaps to
+    #[clap(long, value_enum, default_value = "down")]
+    tie_break: TieBreak,
+    /// Thin out rolls of more than N notes of one pitch within a beat to their first note
+    #[clap(long, value_name = "N")]
+    collapse_rolls: Option<u32>,
+    /// Move notes that snap onto a taken step to the next free one instead of dropping them
+    #[clap(long)]
+    spread: bool,
+    /// Shortest note in ticks; later notes are pushed back to make room
+    #[clap(long, default_value_t = 1)]
+    min_duration_ticks: u32,
+    /// Put each pitch on its own track, named after its GM drum sound
+    #[clap(long)]
+    split_by_pitch: bool,
+    /// What to do with pitches above 127
+    #[clap(long, value_enum, default_value = "error")]
+    out_of_range: OutOfRange,
+    /// Milliseconds of silence before the chart starts
+    #[clap(long, default_value_t = 0)]
+    lead_in: u32,
+    /// Milliseconds of silence after the last note before the tracks end
+    #[clap(long, value_name = "MS", default_value_t = 0)]
+    tail: u32,
+    /// What to do with a gap between events too long for one MIDI delta
+    #[clap(long, value_enum, default_value = "error")]
+    long_gap: LongGap,
+    /// Start with a GM System On message for modules that need a reset
+    #[clap(long)]
+    gm_reset: bool,
+    /// Write the smallest file: zero-velocity note ends and no empty track name
+    #[clap(long)]
+    compact: bool,
+    /// Add a metronome track that clicks on every beat of the time signature
+    #[clap(long)]
+    metronome: bool,
+    /// Metronome sound for the first beat of each bar, as PITCH[:VELOCITY]; default 76:127
+    #[clap(long, value_name = "NOTE", requires = "metronome")]
+    metronome_accent: Option<String>,
+    /// Metronome sound for the other beats, as PITCH[:VELOCITY]; default 77:100
+    #[clap(long, value_name = "NOTE", requires = "metronome")]
+    metronome_beat: Option<String>,
+    /// Play an @lane on a channel, counting from 0, with a GM program; repeat for more lanes
+    #[clap(long, value_name = "LANE:CHANNEL:PROGRAM", value_parser = parse_lane_map)]
+    lane_map: Vec<LaneMap>,
+    /// Let --lane-map put several lanes on one channel when they use the same program, or a lane
+    /// on the channel of one left unmapped
+    #[clap(long)]
+    share_channels: bool,
+    /// Controller events per beat for each @lfo
+    #[clap(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=480))]
+    lfo_resolution: u32,
+    /// Convert only the chart from this time in ms on, moved to start at zero
+    #[clap(long, value_name = "MS", allow_hyphen_values = true, conflicts_with_all = &["grid", "bpm-file"], value_parser = clap::value_parser!(i64).range(-MAX_KEY..=MAX_KEY))]
+    from: Option<Key>,
+    /// Convert only the chart before this time in ms
+    #[clap(long, value_name = "MS", allow_hyphen_values = true, conflicts_with_all = &["grid", "bpm-file"], value_parser = clap::value_parser!(i64).range(-MAX_KEY..=MAX_KEY))]
+    to: Option<Key>,
+    /// What to do with a written duration that crosses --from or --to
+    #[clap(long, value_enum, default_value = "truncate")]
+    clip: Clip,
+    /// Print nothing but errors: no warnings, and no --duration or --auto-division report
     #[clap(short, long)]
-    input: String,
-    #[clap(short, long)]
-    output: String,
+    quiet: bool,
+    /// How to report a failure on stderr
+    #[clap(long, value_enum, default_value = "text")]
+    error_format: ErrorFormat,
+    /// Print the formats that can be read and written, then exit
+    #[clap(long)]
+    list_formats: bool,
 }
 
-pub type Bpm = u32;
-pub type Key = u32;
+fn main() {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        // help and version are not failures
+        Err(e) if e.use_stderr() && raw_error_format() == ErrorFormat::Json => {
+            Err(e).or_fail("failed to read the arguments", ErrorFormat::Json)
+        }
+        Err(e) => e.exit(),
+    };
+    let format = args.error_format;
+    // --emit always has a value, so clap cannot tell when it was left out
+    if args.seconds.is_some() && args.emit != Emit::Click && !args.metronome {
+        Err(UsageError::SecondsWithoutClick).or_fail("failed to read the arguments", format)
+    }
+    if args.list_formats {
+        list_formats();
+        return;
+    }
+    if args.reverse {
+        let input = match &args.input[..] {
+            [input] => input,
+            _ => Err(UsageError::ReverseInputs).or_fail("failed to read the arguments", format),
+        };
+        let bytes = std::fs::read(input).or_fail("failed to read the input file", format);
+        let options = ImportOptions {
+            label_durations: args.label_durations,
+        };
+        let chart = import(&bytes, &options).or_fail("failed to import the input", format);
+        if let Some(path) = args.output {
+            std::fs::write(path, chart).or_fail("failed to write the output file", format);
+        }
+        return;
+    }
+    let tempo_map = args.bpm_file.as_ref().map(|path| {
+        let map_str = std::fs::read_to_string(path).or_fail("failed to read the bpm file", format);
+        parse_tempo_file(&map_str).or_fail("failed to parse the bpm file", format)
+    });
+    let parse_options = ParseOptions {
+        headerless: args.no_magic,
+        tempo: args
+            .bpm
+            .map(timing::tempo_us)
+            .or(args.tempo_us)
+            .or_else(|| Some(timing::tempo_us(tempo_map.as_ref()?[0].1))),
+        input_mode: args.input_mode,
+        middle_c: args.middle_c,
+    };
+    let grid = args.grid.as_ref().map(|path| {
+        let grid_str =
+            std::fs::read_to_string(path).or_fail("failed to read the grid file", format);
+        parse_grid(&grid_str).or_fail("failed to parse the grid", format)
+    });
+    if let Some(dir) = &args.batch {
+        let opts = Opts {
+            parse: parse_options,
+            solve: solve_options(&args, grid, tempo_map, args.division),
+            build: build_options(&args),
+        };
+        let results = batch(&args.input, dir, &opts, args.jobs as usize);
+        let converted = results.iter().filter(|result| result.is_ok()).count();
+        for (input, result) in args.input.iter().zip(&results) {
+            match result {
+                Ok(converted) if !args.quiet => {
+                    for warning in &converted.warnings {
+                        eprintln!("{}: warning: {}", input, warning);
+                    }
+                    println!("{} -> {}", input, converted.path.display());
+                }
+                Ok(_) => {}
+                Err(failure) => match format {
+                    ErrorFormat::Text => {
+                        eprintln!("{}: {}: {}", input, failure.stage, failure.message)
+                    }
+                    ErrorFormat::Json => eprintln!("{}", serde_json::to_string(failure).unwrap()),
+                },
+            }
+        }
+        if !args.quiet {
+            println!("converted {} of {} files", converted, results.len());
+        }
+        std::process::exit(if converted == results.len() { 0 } else { 1 });
+    }
+    let mut inputs = Vec::new();
+    for path in &args.input {
+        let input_str =
+            std::fs::read_to_string(path).or_fail("failed to read the input file", format);
+        inputs.push(parse(&input_str, &parse_options).or_fail("failed to parse the input", format));
+    }
+    if inputs.is_empty() {
+        // --keys is a headerless chart body, and clap makes it require a tempo
+        let keys_options = ParseOptions {
+            headerless: true,
+            ..parse_options
+        };
+        let keys = args.keys.join(",");
+        inputs.push(parse(&keys, &keys_options).or_fail("failed to parse the keys", format));
+    }
+    let mut input = merge(inputs).or_fail("failed to merge the inputs", format);
+    if tempo_map.is_some() && !args.no_magic && !args.input.is_empty() {
+        input
+            .warnings
+            .push("the bpm file overrides the chart's bpm".to_owned());
+    }
+    if !args.quiet {
+        for warning in &input.warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    let division = if args.auto_division {
+        let division = best_division(&input, &AUTO_DIVISIONS);
+        if !args.quiet {
+            println!("division: {}", division);
+        }
+        division
+    } else {
+        args.division
+    };
+    let output = solve(input, &solve_options(&args, grid, tempo_map, division));
+    if !output.merged.is_empty() && !args.quiet {
+        eprintln!("warning: {}", merged_warning(&output));
+    }
+    if args.duration && !args.quiet {
+        let Length { seconds, bars } = length(&output);
+        let bars = format!("{:.2}", bars);
+        let bars = bars.trim_end_matches('0').trim_end_matches('.');
+        println!("duration: {:.2} s, {} bars", seconds, bars);
+    }
+    let path = match &args.output {
+        Some(path) => path,
+        None => return,
+    };
+    let sidecar = args.sidecar.then(|| Sidecar::new(&output));
+    let options = build_options(&args);
+    let output_bin = match args.emit {
+        Emit::Midi | Emit::Click => {
+            build(output, &options).or_fail("failed to build the output", format)
+        }
+        Emit::Events => {
+            let events =
+                timed_events(output, &options).or_fail("failed to build the output", format);
+            let mut json = serde_json::to_string(&events).unwrap();
+            json.push('\n');
+            json.into_bytes().into_boxed_slice()
+        }
+    };
+    std::fs::write(path, output_bin).or_fail("failed to write the output file", format);
+    if let Some(sidecar) = sidecar {
+        let mut json = serde_json::to_string_pretty(&sidecar).unwrap();
+        json.push('\n');
+        // a suffix of its own, so it never replaces a JSON --output
+        let sidecar_path = Path::new(path).with_extension("sidecar.json");
+        std::fs::write(sidecar_path, json).or_fail("failed to write the sidecar file", format);
+    }
+}
 
-pub struct Input {
-    pub bpm: Bpm,
-    pub keys: Vec<Key>,
+/// Says how many notes were lost as duplicates, and where the most of them were, so a grid too
+/// coarse for the chart shows.
+fn merged_warning(output: &Output) -> String {
+    let total: u32 = output.merged.iter().map(|&(_, count)| count).sum();
+    let mut heaviest = output.merged.clone();
+    // the most first, and the earliest of those
+    heaviest.sort_by_key(|&(beat, count)| (Reverse(count), beat));
+    let at: Vec<String> = heaviest
+        .iter()
+        .take(3)
+        .map(|&(beat, count)| format!("{:.0} ms ({})", output.step_ms(beat), count))
+        .collect();
+    let notes = if total == 1 { "note was" } else { "notes were" };
+    format!(
+        "{} {} merged into others on the same beat, the most at {}",
+        total,
+        notes,
+        at.join(", ")
+    )
 }
 
-pub struct Output {
-    pub bpm: Bpm,
-    pub beat: Vec<u32>,
+/// What `--sidecar` writes about the solved chart.
+#[derive(Serialize)]
+struct Sidecar {
+    tempo: Tempo,
+    division: u32,
+    notes: Vec<SidecarNote>,
+    stats: Stats,
 }
 
-#[derive(Error, Debug)]
-pub enum ParseError {
-    #[error("bad magic")]
-    BadMagic,
-    #[error("bad bpm")]
-    BadBpm,
-    #[error("bad key time")]
-    BadKey,
+#[derive(Serialize)]
+struct SidecarNote {
+    beat: u64,
+    /// Where the note's step falls.
+    step_ms: f64,
+    /// How far the note was written from its step; negative when early.
+    error_ms: f64,
+    pitch: Pitch,
+    velocity: Velocity,
+    channel: u8,
 }
 
-#[derive(Error, Debug)]
-pub enum OutputError {
-    #[error("buffer error")]
-    IOError(#[from] std::io::Error),
+#[derive(Serialize)]
+struct Stats {
+    notes: usize,
+    merged: u32,
+    seconds: f64,
+    bars: f64,
+    mean_error_ms: f64,
+    max_error_ms: f64,
 }
 
-fn parse(s: &str) -> Result<Input, ParseError> {
-    use ParseError::*;
-    let mut keys = Vec::new();
-    let mut tokens = s.split([' ', '\n']);
-    // magic
-    if "naive-rhythm" != tokens.next().ok_or(BadMagic)? {
-        return Err(BadMagic);
+impl Sidecar {
+    fn new(output: &Output) -> Self {
+        let notes: Vec<SidecarNote> = output
+            .notes
+            .iter()
+            .map(|hit| {
+                let step_ms = output.step_ms(hit.beat);
+                SidecarNote {
+                    beat: hit.beat,
+                    step_ms,
+                    error_ms: hit.key as f64 - step_ms,
+                    pitch: hit.pitch,
+                    velocity: hit.velocity,
+                    channel: hit.channel,
+                }
+            })
+            .collect();
+        let errors = notes.iter().map(|note| note.error_ms.abs());
+        let Length { seconds, bars } = length(output);
+        let stats = Stats {
+            notes: notes.len(),
+            merged: output.merged.iter().map(|&(_, count)| count).sum(),
+            seconds,
+            bars,
+            mean_error_ms: errors.clone().sum::<f64>() / notes.len().max(1) as f64,
+            max_error_ms: errors.fold(0.0, f64::max),
+        };
+        Sidecar {
+            tempo: output.tempo,
+            division: output.division,
+            notes,
+            stats,
+        }
     }
-    // bpm
-    if "bpm" != tokens.next().ok_or(BadBpm)? {
-        return Err(BadBpm);
+}
+
+fn solve_options(
+    args: &Args,
+    grid: Option<Vec<Key>>,
+    tempo_map: Option<Vec<(Key, Bpm)>>,
+    division: u32,
+) -> SolveOptions {
+    SolveOptions {
+        dedup_by: args.dedup_by,
+        dedup_keep: args.dedup_keep,
+        grid,
+        grid_tempo: args.grid_tempo,
+        tempo_map,
+        division,
+        quantize: args.quantize,
+        tie_break: args.tie_break,
+        collapse_rolls: args.collapse_rolls,
+        spread: args.spread,
+        from: args.from,
+        to: args.to,
+        clip: args.clip,
     }
-    let bpm_str = tokens.next().ok_or(BadBpm)?;
-    let bpm: Bpm = bpm_str.parse().map_err(|_| BadBpm)?;
-    // keys
-    for key_str in tokens {
-        if key_str.is_empty() {
-            continue;
-        }
-        let key: u32 = key_str.parse().map_err(|_| BadKey)?;
-        keys.push(key);
-    }
-    // input
-    Ok(Input { bpm, keys })
-}
-
-pub fn solve(input: Input) -> Output {
-    let bpm = input.bpm;
-    let beat_ms = 60_000 / bpm;
-    let mut beat: Vec<u32> = input
-        .keys
-        .into_iter()
-        .map(|key| {
-            let ans_0 = key / beat_ms;
-            let ans_1 = key / beat_ms + 1;
-            if key - ans_0 * beat_ms <= ans_1 * beat_ms - key {
-                ans_0
-            } else {
-                ans_1
-            }
-        })
-        .collect();
-    beat.sort_unstable();
-    let beat = beat
-        .into_iter()
-        .filter({
-            let mut last = None;
-            move |x| {
-                let ret = last != Some(*x);
-                last = Some(*x);
-                ret
-            }
-        })
-        .collect();
-    Output { bpm, beat }
-}
-
-fn build(output: Output) -> Result<Box<[u8]>, OutputError> {
-    use TrackEventKind::*;
-    let ppq = 480;
-    let bpm = output.bpm;
-    let tempo = 60_000_000 / bpm;
-    let format = Format::Parallel;
-    let timing = Timing::Metrical(u15::new(ppq));
-    let header = Header::new(format, timing);
-    let track0 = vec![
-        TrackEvent {
-            delta: u28::new(0),
-            kind: Meta(MetaMessage::TrackName(&[])),
-        },
-        TrackEvent {
-            delta: u28::new(0),
-            kind: Meta(MetaMessage::TimeSignature(4, 2, 24, 8)),
-        },
-        TrackEvent {
-            delta: u28::new(0),
-            kind: Meta(MetaMessage::Tempo(u24::new(tempo))),
-        },
-        TrackEvent {
-            delta: u28::new(0),
-            kind: Meta(MetaMessage::EndOfTrack),
-        },
-    ];
-    let track1 = {
-        let mut track = vec![];
-        for i in 0..output.beat.len() {
-            let on_delta = if i == 0 { output.beat[0] } else { 0 };
-            track.push(TrackEvent {
-                delta: u28::new(on_delta * 115200 / bpm),
-                kind: Midi {
-                    channel: u4::new(0),
-                    message: MidiMessage::NoteOn {
-                        key: u7::new(60),
-                        vel: u7::new(127),
-                    },
-                },
-            });
-            let off_delta = if i == output.beat.len() - 1 {
-                1
-            } else {
-                output.beat[i + 1] - output.beat[i]
+}
+
+fn build_options(args: &Args) -> BuildOptions {
+    BuildOptions {
+        note_off_style: args.note_off_style,
+        overlap: args.overlap,
+        velocity_curve: args.velocity_curve,
+        normalize_velocity: args.normalize_velocity,
+        min_duration_ticks: args.min_duration_ticks,
+        split_by_pitch: args.split_by_pitch,
+        out_of_range: args.out_of_range,
+        lead_in: args.lead_in,
+        lfo_resolution: args.lfo_resolution,
+        tail: args.tail,
+        long_gap: args.long_gap,
+        gm_reset: args.gm_reset,
+        compact: args.compact,
+        lane_map: args.lane_map.clone(),
+        share_channels: args.share_channels,
+        metronome: (args.metronome || args.emit == Emit::Click).then(|| {
+            let default = Metronome::default();
+            let click = |s: &Option<String>, default: Click| match s {
+                Some(s) => parse_click(s, args.middle_c)
+                    .or_fail("failed to parse a metronome sound", args.error_format),
+                None => default,
             };
-            track.push(TrackEvent {
-                delta: u28::new(off_delta * 115200 / bpm),
-                kind: Midi {
-                    channel: u4::new(0),
-                    message: MidiMessage::NoteOff {
-                        key: u7::new(60),
-                        vel: u7::new(0),
-                    },
-                },
-            });
-        }
-        track.push(TrackEvent {
-            delta: u28::new(0),
-            kind: Meta(MetaMessage::EndOfTrack),
-        });
-        track
-    };
-    let mut smf = Smf::new(header);
-    smf.tracks = vec![track0, track1];
-    let mut binary = Vec::new();
-    smf.write_std(&mut binary)?;
-    Ok(binary.into_boxed_slice())
+            Metronome {
+                accent: click(&args.metronome_accent, default.accent),
+                beat: click(&args.metronome_beat, default.beat),
+                length: args
+                    .seconds
+                    .map_or(0, |seconds| (seconds * 1000.0).round() as u32),
+            }
+        }),
+    }
 }
 
-fn main() {
-    let args = Args::parse();
-    let input_str = std::fs::read_to_string(args.input).expect("failed to read the input file");
-    let input = parse(&input_str).expect("failed to parse the input");
-    let output = solve(input);
-    let output_bin = build(output).expect("failed to build the output");
-    std::fs::write(args.output, output_bin).expect("failed to write the output file");
+/// What one input of a batch wrote, and the warnings it would print on its own.
+struct Converted {
+    path: PathBuf,
+    warnings: Vec<String>,
+}
+
+/// Converts each input on its own into `dir`, `jobs` at a time, returning what each wrote or
+/// why it failed, in input order.
+fn batch(
+    inputs: &[String],
+    dir: &Path,
+    opts: &Opts,
+    jobs: usize,
+) -> Vec<Result<Converted, Failure>> {
+    // the first input named after a file keeps it, rather than a faster one overwriting it
+    let paths: Vec<PathBuf> = inputs.iter().map(|input| output_path(input, dir)).collect();
+    let mut taken = HashSet::new();
+    let shared: Vec<bool> = paths.iter().map(|path| !taken.insert(path)).collect();
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<Converted, Failure>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(inputs.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match inputs.get(i) {
+                            Some(input) => {
+                                let result = if shared[i] {
+                                    let e =
+                                        UsageError::SharedOutput(paths[i].display().to_string());
+                                    Err(Failure::new("failed to name the output file", &e))
+                                } else {
+                                    convert_file(input, &paths[i], opts)
+                                };
+                                let input = Some(input.clone());
+                                done.push((i, result.map_err(|e| Failure { input, ..e })));
+                            }
+                            None => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Names an input's output in `dir` after its whole file stem, dots and all.
+fn output_path(input: &str, dir: &Path) -> PathBuf {
+    let mut name = Path::new(input).file_stem().unwrap_or_default().to_owned();
+    name.push(".mid");
+    dir.join(name)
+}
+
+/// Converts one chart into `path`, warning of what converting it alone would.
+fn convert_file(input: &str, path: &Path, opts: &Opts) -> Result<Converted, Failure> {
+    let chart = std::fs::read_to_string(input)
+        .map_err(|e| Failure::new("failed to read the input file", &e))?;
+    let input =
+        parse(&chart, &opts.parse).map_err(|e| Failure::new("failed to parse the input", &e))?;
+    let mut warnings = input.warnings.clone();
+    if opts.solve.tempo_map.is_some() && !opts.parse.headerless {
+        warnings.push("the bpm file overrides the chart's bpm".to_owned());
+    }
+    let output = solve(input, &opts.solve);
+    if !output.merged.is_empty() {
+        warnings.push(merged_warning(&output));
+    }
+    let bytes =
+        build(output, &opts.build).map_err(|e| Failure::new("failed to build the output", &e))?;
+    std::fs::write(path, bytes).map_err(|e| Failure::new("failed to write the output file", &e))?;
+    Ok(Converted {
+        path: path.to_owned(),
+        warnings,
+    })
 }
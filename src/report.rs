@@ -0,0 +1,59 @@
+use crate::Place;
+
+/// An error that can name what went wrong, for wrappers that tell one failure from another.
+pub trait Report: std::error::Error {
+    /// The variant's name, such as "BadPitch", or for a file error its kind, such as "NotFound".
+    fn kind(&self) -> String;
+
+    /// Where in a chart it happened, for errors that come from reading one.
+    fn place(&self) -> Option<&Place> {
+        None
+    }
+}
+
+impl Report for std::io::Error {
+    fn kind(&self) -> String {
+        // each io::ErrorKind's Debug form is its bare name
+        format!("{:?}", std::io::Error::kind(self))
+    }
+}
+
+#[cfg(feature = "cli")]
+impl Report for clap::Error {
+    fn kind(&self) -> String {
+        format!("{:?}", clap::Error::kind(self))
+    }
+}
+
+/// Defines an error enum along with a `Report` impl that names each variant after itself, so
+/// the names cannot drift from the variants.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! error_kinds {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident $(($($(#[$field_meta:meta])* $field:ty),* $(,)?))?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant $(($($(#[$field_meta])* $field),*))?
+            ),*
+        }
+
+        impl $crate::Report for $name {
+            fn kind(&self) -> String {
+                match self {
+                    $($name::$variant { .. } => stringify!($variant),)*
+                }
+                .to_owned()
+            }
+        }
+    };
+}
@@ -0,0 +1,35 @@
+//! Conversions between beats per minute, tempo, milliseconds and ticks.
+//!
+//! Tempo is in microseconds per quarter note, as MIDI stores it, and a beat is a quarter note.
+//! Tick conversions round to the nearest tick or millisecond, and count ticks in u64 so a long
+//! chart never wraps; whether a gap fits a MIDI delta is for the writer to check.
+
+use crate::{Bpm, Tempo};
+
+/// Microseconds per quarter note at `bpm`.
+pub fn tempo_us(bpm: Bpm) -> Tempo {
+    60_000_000 / bpm
+}
+
+/// Length of one beat in milliseconds.
+pub fn beat_ms(tempo: Tempo) -> f64 {
+    tempo as f64 / 1000.0
+}
+
+/// Ticks in `ms` milliseconds at `tempo` with `ppq` ticks per quarter note.
+pub fn ms_to_ticks(ms: u64, tempo: Tempo, ppq: u16) -> u64 {
+    let tempo = tempo as u64;
+    (ms * 1000 * ppq as u64 + tempo / 2) / tempo
+}
+
+/// Milliseconds in `ticks` ticks at `tempo` with `ppq` ticks per quarter note.
+pub fn ticks_to_ms(ticks: u64, tempo: Tempo, ppq: u16) -> u64 {
+    let per_ms = 1000 * ppq as u64;
+    (ticks * tempo as u64 + per_ms / 2) / per_ms
+}
+
+/// Ticks in `steps` grid steps of `division` per beat with `ppq` ticks per quarter note.
+pub fn steps_to_ticks(steps: u64, division: u32, ppq: u16) -> u64 {
+    let division = division as u64;
+    (steps * ppq as u64 + division / 2) / division
+}
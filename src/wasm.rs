@@ -0,0 +1,7 @@
+use wasm_bindgen::prelude::*;
+
+/// Converts a chart to MIDI bytes with the default options.
+#[wasm_bindgen]
+pub fn convert(input: &str) -> Result<Vec<u8>, JsError> {
+    super::convert(input, &super::Opts::default()).map_err(|e| JsError::new(&e.to_string()))
+}
@@ -0,0 +1,1294 @@
+//! Checks individual options by running the binary on small charts.
+
+use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn tmp(file: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(file)
+}
+
+/// Runs the binary with the given arguments and an output path, returning the MIDI bytes.
+fn run(name: &str, args: &[&OsStr]) -> Vec<u8> {
+    let output = tmp(&format!("cli-{}.mid", name));
+    let status = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--output")
+        .arg(&output)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "naive-rhythm failed");
+    std::fs::read(&output).unwrap()
+}
+
+/// Runs the binary on `chart` and returns whether it succeeded.
+fn succeeds(name: &str, chart: &str, args: &[&str]) -> bool {
+    let input = tmp(&format!("cli-{}.txt", name));
+    std::fs::write(&input, chart).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&input)
+        .arg("--output")
+        .arg(tmp(&format!("cli-{}.mid", name)))
+        .args(args)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
+
+/// Runs the binary on `chart` and returns its exit status and what it printed, whether or not
+/// it succeeded.
+fn run_output(name: &str, chart: &str, args: &[&str]) -> std::process::Output {
+    let input = tmp(&format!("cli-{}.txt", name));
+    std::fs::write(&input, chart).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&input)
+        .arg("--output")
+        .arg(tmp(&format!("cli-{}.mid", name)))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Converts `chart` with the given extra arguments and returns the MIDI bytes.
+fn convert(name: &str, chart: &str, args: &[&str]) -> Vec<u8> {
+    let input = tmp(&format!("cli-{}.txt", name));
+    std::fs::write(&input, chart).unwrap();
+    let mut all: Vec<&OsStr> = vec!["--input".as_ref(), input.as_ref()];
+    all.extend(args.iter().map(OsStr::new));
+    run(name, &all)
+}
+
+/// Pitches of the NoteOn events in a track, in order.
+fn note_ons(track: &[midly::TrackEvent]) -> Vec<u8> {
+    track
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if vel > 0 => Some(key.as_int()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn split_by_pitch() {
+    let chart = "naive-rhythm bpm 120 0:36 0:38 500:36 1000:38 1500:36";
+    let bytes = convert("split_by_pitch", chart, &["--split-by-pitch"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 3);
+    assert_eq!(
+        smf.tracks[1][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Bass Drum 1"))
+    );
+    assert_eq!(note_ons(&smf.tracks[1]), [36, 36, 36]);
+    assert_eq!(
+        smf.tracks[2][0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Acoustic Snare"))
+    );
+    assert_eq!(note_ons(&smf.tracks[2]), [38, 38]);
+}
+
+#[test]
+fn keys_from_arguments() {
+    let args = ["--bpm", "120", "--keys", "0,500:38"].map(OsStr::new);
+    let from_args = run("keys_from_arguments", &args);
+    let from_file = convert("keys_from_file", "naive-rhythm bpm 120 0 500:38", &[]);
+    assert_eq!(from_args, from_file);
+}
+
+#[test]
+fn keys_conflict_with_input() {
+    let status = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args(["--input", "chart.txt", "--output", "out.mid"])
+        .args(["--bpm", "120", "--keys", "0,500"])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn out_of_range_error() {
+    let chart = "naive-rhythm bpm 120 0:60 500:130";
+    assert!(!succeeds("out_of_range_error", chart, &[]));
+}
+
+#[test]
+fn out_of_range_clamp() {
+    let chart = "naive-rhythm bpm 120 0:60 500:130";
+    let bytes = convert("out_of_range_clamp", chart, &["--out-of-range", "clamp"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(note_ons(&smf.tracks[1]), [60, 127]);
+}
+
+#[test]
+fn out_of_range_octave_wrap() {
+    let chart = "naive-rhythm bpm 120 0:60 500:130";
+    let bytes = convert(
+        "out_of_range_octave_wrap",
+        chart,
+        &["--out-of-range", "octave-wrap"],
+    );
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(note_ons(&smf.tracks[1]), [60, 118]);
+}
+
+#[test]
+fn lead_in() {
+    let delayed = convert(
+        "lead_in",
+        "naive-rhythm bpm 120 0 500",
+        &["--lead-in", "1000"],
+    );
+    let shifted = convert("lead_in_shifted", "naive-rhythm bpm 120 1000 1500", &[]);
+    let delayed = Smf::parse(&delayed).unwrap();
+    let shifted = Smf::parse(&shifted).unwrap();
+    assert_ne!(delayed.tracks[1][0].delta, 0);
+    assert_eq!(delayed.tracks, shifted.tracks);
+}
+
+#[test]
+fn tail() {
+    let chart = "naive-rhythm bpm 120 0 500";
+    let end_delta = |bytes: &[u8]| {
+        let smf = Smf::parse(bytes).unwrap();
+        let end = smf.tracks[1].last().unwrap();
+        assert_eq!(end.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+        end.delta.as_int()
+    };
+    assert_eq!(end_delta(&convert("tail_none", chart, &[])), 0);
+    assert_eq!(end_delta(&convert("tail", chart, &["--tail", "1000"])), 960);
+}
+
+#[test]
+fn min_duration_ticks() {
+    // adjacent steps of 5 ticks, each held for at least 20
+    let chart = "naive-rhythm bpm 120 0 6";
+    let args = ["--division", "96", "--min-duration-ticks", "20"];
+    let bytes = convert("min_duration_ticks", chart, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(
+        note_ticks(&smf.tracks[1]),
+        [(0, true), (20, false), (20, true), (40, false)]
+    );
+}
+
+#[test]
+fn end_directive() {
+    let chart = "naive-rhythm bpm 120 0 500 @end 10000";
+    let smf_bytes = convert("end_directive", chart, &[]);
+    let smf = Smf::parse(&smf_bytes).unwrap();
+    let track = &smf.tracks[1];
+    assert_eq!(
+        track.last().unwrap().kind,
+        TrackEventKind::Meta(MetaMessage::EndOfTrack)
+    );
+    let end: u32 = track.iter().map(|event| event.delta.as_int()).sum();
+    assert_eq!(end, 9600);
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(tmp("cli-end_directive.txt"))
+        .arg("--duration")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "duration: 10.00 s, 5 bars\n"
+    );
+}
+
+#[test]
+fn merge_inputs() {
+    let kick = tmp("cli-merge-kick.txt");
+    let snare = tmp("cli-merge-snare.txt");
+    std::fs::write(&kick, "naive-rhythm bpm 120 0:36").unwrap();
+    std::fs::write(&snare, "naive-rhythm bpm 120 500:38").unwrap();
+    let args: [&OsStr; 4] = [
+        "--input".as_ref(),
+        kick.as_ref(),
+        "--input".as_ref(),
+        snare.as_ref(),
+    ];
+    let bytes = run("merge_inputs", &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 3);
+    assert_eq!(note_ons(&smf.tracks[1]), [36]);
+    assert_eq!(note_ons(&smf.tracks[2]), [38]);
+    let channel = |track: &[midly::TrackEvent]| match track[0].kind {
+        TrackEventKind::Midi { channel, .. } => channel.as_int(),
+        _ => panic!("expected a note"),
+    };
+    assert_eq!(channel(&smf.tracks[1]), 0);
+    assert_eq!(channel(&smf.tracks[2]), 1);
+}
+
+#[test]
+fn merge_needs_one_tempo() {
+    let slow = tmp("cli-merge-slow.txt");
+    std::fs::write(&slow, "naive-rhythm bpm 90 0").unwrap();
+    let fast = "naive-rhythm bpm 120 0";
+    let slow = slow.to_str().unwrap();
+    assert!(!succeeds("merge_needs_one_tempo", fast, &["--input", slow]));
+}
+
+#[test]
+fn duration() {
+    let keys = (0..=16)
+        .map(|beat| (beat * 500).to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args(["--bpm", "120", "--keys", &keys, "--duration"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "duration: 8.00 s, 4 bars\n"
+    );
+}
+
+#[test]
+fn list_formats() {
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--list-formats")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    let (inputs, outputs) = listing.split_once("output formats:\n").unwrap();
+    let names = |section: &str| -> Vec<String> {
+        section
+            .lines()
+            .filter_map(|line| line.strip_prefix("  "))
+            .map(|line| line.split_whitespace().next().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(names(inputs), ["chart", "midi"]);
+    assert_eq!(names(outputs), ["midi", "events", "click", "chart"]);
+}
+
+#[test]
+fn duration_of_empty_chart() {
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args(["--bpm", "120", "--keys", "", "--duration"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "duration: 0.00 s, 0 bars\n"
+    );
+}
+
+/// Velocities of the NoteOn events in a track, in order.
+fn velocities(track: &[midly::TrackEvent]) -> Vec<u8> {
+    track
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. },
+                ..
+            } if vel > 0 => Some(vel.as_int()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn dedup_keep_first() {
+    let chart = "naive-rhythm bpm 120 0::40 10::100 500";
+    let bytes = convert("dedup_keep_first", chart, &["--dedup-keep", "first"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(velocities(&smf.tracks[1]), [40, 127]);
+}
+
+#[test]
+fn dedup_keep_loudest() {
+    let chart = "naive-rhythm bpm 120 0::40 10::100 500";
+    let bytes = convert("dedup_keep_loudest", chart, &["--dedup-keep", "loudest"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(velocities(&smf.tracks[1]), [100, 127]);
+}
+
+#[test]
+fn grid() {
+    let grid = tmp("cli-grid-taps.txt");
+    std::fs::write(&grid, "0 480 1010 1450\n2100 # drifting\n").unwrap();
+    let grid = grid.to_str().unwrap();
+    let chart = "naive-rhythm bpm 120 20 700 1000 1800 2600";
+    let snapped = convert("grid", chart, &["--grid", grid]);
+    let regular = convert("grid_regular", "naive-rhythm bpm 120 0 500 1000 2000", &[]);
+    assert_eq!(
+        Smf::parse(&snapped).unwrap().tracks,
+        Smf::parse(&regular).unwrap().tracks
+    );
+}
+
+#[test]
+fn grid_tempo() {
+    let grid = tmp("cli-grid_tempo-taps.txt");
+    std::fs::write(&grid, "0 600 1100 1500 1800\n").unwrap();
+    let grid = grid.to_str().unwrap();
+    let chart = "naive-rhythm bpm 120 0 600 1100 1500 1800";
+    let bytes = convert("grid_tempo", chart, &["--grid", grid, "--grid-tempo"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let mut tempos = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+            tempos.push((tick, tempo.as_int()));
+        }
+    }
+    let expected = [
+        (0, 600_000),
+        (480, 500_000),
+        (960, 400_000),
+        (1440, 300_000),
+    ];
+    assert_eq!(tempos, expected);
+    assert_eq!(note_ons(&smf.tracks[1]).len(), 5);
+}
+
+#[test]
+fn events_follow_tempo_changes() {
+    let grid = tmp("cli-events_follow_tempo_changes-taps.txt");
+    std::fs::write(&grid, "0 1000 2000 2500 3000\n").unwrap();
+    let grid = grid.to_str().unwrap();
+    let chart = "naive-rhythm bpm 120 0 1000 2000 2500 3000 @pedal down 3000";
+    let args = ["--grid", grid, "--grid-tempo"];
+    let bytes = convert("events_follow_tempo_changes", chart, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let (mut last_on, mut pedal) = (None, None);
+    for event in &smf.tracks[1] {
+        tick += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. },
+                ..
+            } if vel > 0 => last_on = Some(tick),
+            TrackEventKind::Midi {
+                message: MidiMessage::Controller { .. },
+                ..
+            } => pedal = Some(tick),
+            _ => {}
+        }
+    }
+    assert_eq!(last_on, Some(4 * 480));
+    assert_eq!(pedal, last_on);
+}
+
+#[test]
+fn bpm_file() {
+    let bpm_file = tmp("cli-bpm_file-bpm.txt");
+    std::fs::write(&bpm_file, "120\n").unwrap();
+    let input = tmp("cli-bpm_file-chart.txt");
+    std::fs::write(&input, "naive-rhythm bpm 60 0 1000").unwrap();
+    let output = tmp("cli-bpm_file.mid");
+    let run = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&input)
+        .arg("--output")
+        .arg(&output)
+        .arg("--bpm-file")
+        .arg(&bpm_file)
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    let stderr = String::from_utf8(run.stderr).unwrap();
+    assert_eq!(stderr, "warning: the bpm file overrides the chart's bpm\n");
+    let overridden = std::fs::read(&output).unwrap();
+    let at_120 = convert("bpm_file_120", "naive-rhythm bpm 120 0 1000", &[]);
+    assert_eq!(
+        Smf::parse(&overridden).unwrap().tracks,
+        Smf::parse(&at_120).unwrap().tracks
+    );
+}
+
+#[test]
+fn bpm_file_tempo_map() {
+    let bpm_file = tmp("cli-bpm_file_tempo_map-bpm.txt");
+    std::fs::write(&bpm_file, "0 120\n2000 60\n").unwrap();
+    let bpm_file = bpm_file.to_str().unwrap();
+    let chart = "naive-rhythm bpm 120 0 1000 3000";
+    let bytes = convert("bpm_file_tempo_map", chart, &["--bpm-file", bpm_file]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let mut tempos = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+            tempos.push((tick, tempo.as_int()));
+        }
+    }
+    assert_eq!(tempos, [(0, 500_000), (1920, 1_000_000)]);
+    let regular = convert("bpm_file_regular", "naive-rhythm bpm 120 0 1000 2500", &[]);
+    assert_eq!(
+        Smf::parse(&regular).unwrap().tracks[1],
+        smf.tracks[1],
+        "notes on beats 0, 2 and 5"
+    );
+}
+
+#[test]
+fn gm_reset() {
+    let bytes = convert("gm_reset", "naive-rhythm bpm 120 0", &["--gm-reset"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let first = &smf.tracks[0][0];
+    assert_eq!(first.delta, 0);
+    assert_eq!(
+        first.kind,
+        TrackEventKind::SysEx(&[0x7E, 0x7F, 0x09, 0x01, 0xF7])
+    );
+    let raw = [0x00, 0xF0, 0x05, 0x7E, 0x7F, 0x09, 0x01, 0xF7];
+    assert!(bytes.windows(raw.len()).any(|window| window == raw));
+}
+
+#[test]
+fn magic_alias() {
+    let aliased = convert("magic_alias", "nr bpm 120 0 500", &[]);
+    let full = convert("magic_full", "naive-rhythm bpm 120 0 500", &[]);
+    assert_eq!(aliased, full);
+}
+
+#[test]
+fn no_magic() {
+    let headerless = convert("no_magic", "0 500 1000", &["--no-magic", "--bpm", "120"]);
+    let full = convert("no_magic_full", "naive-rhythm bpm 120 0 500 1000", &[]);
+    assert_eq!(headerless, full);
+    assert!(!succeeds("no_magic_rejected", "0 500 1000", &[]));
+}
+
+#[test]
+fn auto_division() {
+    let chart = tmp("cli-auto_division.txt");
+    std::fs::write(&chart, "naive-rhythm bpm 120 0 250 500 750 1000 1250").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&chart)
+        .args(["--auto-division", "--duration"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "division: 2\nduration: 1.25 s, 0.62 bars\n"
+    );
+}
+
+#[test]
+fn velocity_curve() {
+    let chart = "naive-rhythm bpm 120 0::64 500::127";
+    let linear = convert("velocity_curve_linear", chart, &[]);
+    let exp = convert("velocity_curve_exp", chart, &["--velocity-curve", "exp"]);
+    let linear = velocities(&Smf::parse(&linear).unwrap().tracks[1]);
+    let exp = velocities(&Smf::parse(&exp).unwrap().tracks[1]);
+    assert_eq!(linear, [64, 127]);
+    assert!(exp[0] < linear[0]);
+    assert_eq!(exp[1], 127);
+}
+
+#[test]
+fn normalize_velocity() {
+    let chart = "naive-rhythm bpm 120 0::40 500::80";
+    let bytes = convert(
+        "normalize_velocity",
+        chart,
+        &["--normalize-velocity", "120"],
+    );
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(velocities(&smf.tracks[1]), [60, 120]);
+}
+
+#[test]
+fn bend() {
+    let chart =
+        "naive-rhythm bpm 120 0 500 @bend 500 12288 @bend 750 -1.0 @bend 1000 2.0 @bend 1250 -2.0";
+    let bytes = convert("bend", chart, &[]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let mut seen = Vec::new();
+    for event in &smf.tracks[1] {
+        tick += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::PitchBend { bend },
+                ..
+            } => seen.push(("bend", tick, bend.0.as_int())),
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { key, vel },
+                ..
+            } if vel > 0 => seen.push(("on", tick, key.as_int() as u16)),
+            _ => {}
+        }
+    }
+    let beat = seen[2].1;
+    assert_eq!(
+        seen,
+        [
+            ("on", 0, 60),
+            ("bend", beat, 12288),
+            ("on", beat, 60),
+            ("bend", beat * 3 / 2, 4096),
+            ("bend", beat * 2, 16383),
+            ("bend", beat * 5 / 2, 0),
+        ]
+    );
+}
+
+#[test]
+fn aftertouch() {
+    let chart = "naive-rhythm bpm 120 0 @aftertouch 0 90 @poly 0 60 40";
+    let bytes = convert("aftertouch", chart, &[]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let messages: Vec<_> = smf.tracks[1]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message, .. } => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        messages[..2],
+        [
+            MidiMessage::ChannelAftertouch { vel: 90.into() },
+            MidiMessage::Aftertouch {
+                key: 60.into(),
+                vel: 40.into()
+            },
+        ]
+    );
+    assert!(!succeeds(
+        "aftertouch_range",
+        "naive-rhythm bpm 120 @poly 0 60 128",
+        &[]
+    ));
+}
+
+#[test]
+fn collapse_rolls() {
+    let roll = (0..16)
+        .map(|i| format!("{}:38", i * 1000 / 16))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let chart = format!("naive-rhythm bpm 60 {} 1000:38", roll);
+    let args = ["--division", "16"];
+    let full = convert("collapse_rolls_full", &chart, &args);
+    assert_eq!(note_ons(&Smf::parse(&full).unwrap().tracks[1]).len(), 17);
+    let args = ["--division", "16", "--collapse-rolls", "8"];
+    let collapsed = convert("collapse_rolls", &chart, &args);
+    assert_eq!(
+        note_ons(&Smf::parse(&collapsed).unwrap().tracks[1]),
+        [38, 38]
+    );
+}
+
+#[test]
+fn compact() {
+    let chart = "naive-rhythm bpm 120 0 0:38 500 1000:38 1500";
+    let standard = convert("compact_standard", chart, &[]);
+    let compact = convert("compact", chart, &["--compact"]);
+    assert!(compact.len() < standard.len());
+    // the same music once note ends and empty names are spelled one way
+    let normalize = |bytes: &[u8]| -> Vec<Vec<(u32, String)>> {
+        let smf = Smf::parse(bytes).unwrap();
+        smf.tracks
+            .iter()
+            .map(|track| {
+                let mut tick = 0;
+                let mut events = Vec::new();
+                for event in track {
+                    tick += event.delta.as_int();
+                    let kind = match event.kind {
+                        TrackEventKind::Meta(MetaMessage::TrackName(b"")) => continue,
+                        TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOn { key, vel },
+                        } if vel == 0 => TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::NoteOff { key, vel },
+                        },
+                        kind => kind,
+                    };
+                    events.push((tick, format!("{:?}", kind)));
+                }
+                events
+            })
+            .collect()
+    };
+    assert_eq!(normalize(&compact), normalize(&standard));
+}
+
+#[test]
+fn spread() {
+    let chart = "naive-rhythm bpm 120 0 100 1500";
+    let dropped = convert("spread_dropped", chart, &[]);
+    assert_eq!(note_ons(&Smf::parse(&dropped).unwrap().tracks[1]).len(), 2);
+    let spread = convert("spread", chart, &["--spread"]);
+    let adjacent = convert("spread_adjacent", "naive-rhythm bpm 120 0 500 1500", &[]);
+    assert_eq!(
+        Smf::parse(&spread).unwrap().tracks,
+        Smf::parse(&adjacent).unwrap().tracks
+    );
+    // a duplicate on the last step a key can snap to still has a step after it
+    let last = "naive-rhythm bpm 60000 4294967295 4294967295";
+    let args = ["--spread", "--division", "96", "--long-gap", "split"];
+    let spread_last = convert("spread_last", last, &args);
+    assert_eq!(
+        note_ons(&Smf::parse(&spread_last).unwrap().tracks[1]).len(),
+        2
+    );
+}
+
+#[test]
+fn pedal() {
+    let chart = "naive-rhythm bpm 120 @pedal down 0 0:60 500:64 @pedal up 1000";
+    let bytes = convert("pedal", chart, &[]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let messages: Vec<_> = smf.tracks[1]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message, .. } => Some(message),
+            _ => None,
+        })
+        .collect();
+    let pedal = |value: u8| MidiMessage::Controller {
+        controller: 64.into(),
+        value: value.into(),
+    };
+    assert_eq!(messages.first(), Some(&pedal(127)));
+    assert_eq!(messages.last(), Some(&pedal(0)));
+    assert_eq!(
+        messages
+            .iter()
+            .filter(|m| matches!(m, MidiMessage::Controller { .. }))
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn metronome_accents() {
+    let chart = "naive-rhythm bpm 120 @timesig 3/4 at 0 0 2500";
+    let args = [
+        "--metronome",
+        "--metronome-accent",
+        "56",
+        "--metronome-beat",
+        "37:80",
+    ];
+    let bytes = convert("metronome_accents", chart, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    let click = smf.tracks.last().unwrap();
+    assert_eq!(
+        click[0].kind,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"Metronome"))
+    );
+    assert_eq!(note_ons(click), [56, 37, 37, 56, 37, 37]);
+    assert_eq!(velocities(click), [127, 80, 80, 127, 80, 80]);
+}
+
+#[test]
+fn metronome_rebars() {
+    // a bar of 4/4 quarters, then a bar of 6/8 eighths
+    let chart = "naive-rhythm bpm 120 @timesig 6/8 at 2000 @end 3500 0 3000";
+    let args = [
+        "--metronome",
+        "--metronome-accent",
+        "56",
+        "--metronome-beat",
+        "37",
+    ];
+    let bytes = convert("metronome_rebars", chart, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    let click = smf.tracks.last().unwrap();
+    let onsets: Vec<u32> = note_ticks(click)
+        .into_iter()
+        .filter(|&(_, on)| on)
+        .map(|(tick, _)| tick)
+        .collect();
+    assert_eq!(
+        onsets,
+        [0, 480, 960, 1440, 1920, 2160, 2400, 2640, 2880, 3120]
+    );
+    assert_eq!(note_ons(click), [56, 37, 37, 37, 56, 37, 37, 37, 37, 37]);
+}
+
+#[test]
+fn metadata() {
+    let chart = "naive-rhythm\ntitle: My Song\nartist: X # and friends\nbpm 120\n0\n";
+    let bytes = convert("metadata", chart, &[]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let metas: Vec<_> = smf.tracks[0].iter().map(|event| event.kind).collect();
+    assert!(metas.contains(&TrackEventKind::Meta(MetaMessage::TrackName(b"My Song"))));
+    assert!(metas.contains(&TrackEventKind::Meta(MetaMessage::Text(b"X"))));
+}
+
+#[test]
+fn unknown_metadata_warns() {
+    let chart = "naive-rhythm\nalbum: Y\nbpm 120\n0\n";
+    let output = run_output("unknown_metadata", chart, &[]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "warning: unknown metadata key \"album\"\n");
+}
+
+#[test]
+fn lane_map() {
+    let chart = "naive-rhythm bpm 120 @lane 0 0:36 500:36 @lane 1 0:60 500:62";
+    let args = ["--lane-map", "0:9:0", "--lane-map", "1:1:40"];
+    let bytes = convert("lane_map", chart, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 3);
+    let first = |track: &[midly::TrackEvent]| match track[0].kind {
+        TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+        _ => panic!("expected a program change"),
+    };
+    let program = |program: u8| MidiMessage::ProgramChange {
+        program: program.into(),
+    };
+    assert_eq!(first(&smf.tracks[1]), (1, program(40)));
+    assert_eq!(note_ons(&smf.tracks[1]), [60, 62]);
+    assert_eq!(first(&smf.tracks[2]), (9, program(0)));
+    assert_eq!(note_ons(&smf.tracks[2]), [36, 36]);
+}
+
+#[test]
+fn lane_map_shared_channel() {
+    let chart = "naive-rhythm bpm 120 @lane 0 0 @lane 1 500";
+    let clash = ["--lane-map", "0:2:5", "--lane-map", "1:2:5"];
+    assert!(!succeeds("lane_map_clash", chart, &clash));
+    let shared = [
+        "--lane-map",
+        "0:2:5",
+        "--lane-map",
+        "1:2:5",
+        "--share-channels",
+    ];
+    assert!(succeeds("lane_map_shared", chart, &shared));
+    let conflict = [
+        "--lane-map",
+        "0:2:5",
+        "--lane-map",
+        "1:2:6",
+        "--share-channels",
+    ];
+    assert!(!succeeds("lane_map_conflict", chart, &conflict));
+    // lane 0 is left on its chart's channel 0, which lane 1 would take over
+    let unmapped = ["--lane-map", "1:0:40"];
+    assert!(!succeeds("lane_map_unmapped", chart, &unmapped));
+    let unmapped_shared = ["--lane-map", "1:0:40", "--share-channels"];
+    assert!(succeeds(
+        "lane_map_unmapped_shared",
+        chart,
+        &unmapped_shared
+    ));
+}
+
+#[test]
+fn quiet() {
+    let chart = "naive-rhythm\nalbum: Y\nbpm 120\n0 250\n";
+    let output = run_output(
+        "quiet",
+        chart,
+        &["--duration", "--auto-division", "--quiet"],
+    );
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+}
+
+/// Ticks of the note starts and ends in a track, with whether each is a start.
+fn note_ticks(track: &[midly::TrackEvent]) -> Vec<(u32, bool)> {
+    let mut tick = 0;
+    let mut ticks = Vec::new();
+    for event in track {
+        tick += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. },
+                ..
+            } => ticks.push((tick, vel > 0)),
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            } => ticks.push((tick, false)),
+            _ => {}
+        }
+    }
+    ticks
+}
+
+/// A note held for a beat and a half, struck again after a beat for a fifth of one.
+const OVERLAPPING: &str = "naive-rhythm bpm 120 0:38:100:750 500:38:100:100";
+
+#[test]
+fn overlap_truncate() {
+    let bytes = convert("overlap_truncate", OVERLAPPING, &[]);
+    let ticks = note_ticks(&Smf::parse(&bytes).unwrap().tracks[1]);
+    assert_eq!(ticks, [(0, true), (480, false), (480, true), (576, false)]);
+}
+
+#[test]
+fn overlap_merge() {
+    let bytes = convert("overlap_merge", OVERLAPPING, &["--overlap", "merge"]);
+    let ticks = note_ticks(&Smf::parse(&bytes).unwrap().tracks[1]);
+    assert_eq!(ticks, [(0, true), (720, false)]);
+}
+
+#[test]
+fn overlap_retrigger() {
+    let args = ["--overlap", "retrigger"];
+    let bytes = convert("overlap_retrigger", OVERLAPPING, &args);
+    let ticks = note_ticks(&Smf::parse(&bytes).unwrap().tracks[1]);
+    assert_eq!(ticks, [(0, true), (480, false), (480, true), (720, false)]);
+}
+
+#[test]
+fn emit_events() {
+    let chart = "naive-rhythm bpm 120 @pedal down 0 0:38 500:36:90";
+    let json = convert("emit_events", chart, &["--emit", "events"]);
+    let expected = concat!(
+        r#"[{"time_ms":0,"type":"controller","channel":0,"controller":64,"value":127},"#,
+        r#"{"time_ms":0,"type":"note_on","pitch":38,"velocity":127,"channel":0},"#,
+        r#"{"time_ms":500,"type":"note_off","pitch":38,"velocity":0,"channel":0},"#,
+        r#"{"time_ms":500,"type":"note_on","pitch":36,"velocity":90,"channel":0},"#,
+        r#"{"time_ms":1000,"type":"note_off","pitch":36,"velocity":0,"channel":0}]"#,
+        "\n",
+    );
+    assert_eq!(String::from_utf8(json).unwrap(), expected);
+}
+
+#[test]
+fn batch_jobs() {
+    let dir = tmp("cli-batch");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut inputs = Vec::new();
+    for i in 0..12 {
+        let input = tmp(&format!("cli-batch-{}.txt", i));
+        let chart = if i == 5 {
+            "not a chart".to_owned()
+        } else {
+            format!("naive-rhythm bpm 120 0 {}", 250 * (i + 1))
+        };
+        std::fs::write(&input, chart).unwrap();
+        inputs.push(input);
+    }
+    let mut command = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"));
+    for input in &inputs {
+        command.arg("--input").arg(input);
+    }
+    let output = command
+        .arg("--batch")
+        .arg(&dir)
+        .args(["--jobs", "4"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 12);
+    assert_eq!(lines[11], "converted 11 of 12 files");
+    for (i, input) in inputs.iter().enumerate().filter(|&(i, _)| i != 5) {
+        let line = if i < 5 { lines[i] } else { lines[i - 1] };
+        let path = dir.join(format!("cli-batch-{}.mid", i));
+        assert_eq!(line, format!("{} -> {}", input.display(), path.display()));
+        let chart = format!("naive-rhythm bpm 120 0 {}", 250 * (i + 1));
+        let single = convert(&format!("batch_single_{}", i), &chart, &[]);
+        assert_eq!(std::fs::read(path).unwrap(), single);
+    }
+    // 250 ms is half a beat at division 1, so the first chart merges its second note
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        format!(
+            "{}: warning: 1 note was merged into others on the same beat, the most at 0 ms (1)\n\
+             {}: failed to parse the input: bad magic on line 1 at \"not\"\n",
+            inputs[0].display(),
+            inputs[5].display()
+        )
+    );
+    // dots before the extension stay in the name, and a stem taken already fails
+    let other = tmp("cli-batch-other");
+    std::fs::create_dir_all(&other).unwrap();
+    let named = [
+        tmp("cli-batch-dots.v1.txt"),
+        tmp("cli-batch-dots.v2.txt"),
+        other.join("cli-batch-dots.v1.txt"),
+    ];
+    for input in &named {
+        std::fs::write(input, "naive-rhythm bpm 120 0 500").unwrap();
+    }
+    let mut command = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"));
+    for input in &named {
+        command.arg("--input").arg(input);
+    }
+    let output = command
+        .arg("--batch")
+        .arg(&dir)
+        .args(["--jobs", "3"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let expected = [
+        format!(
+            "{} -> {}",
+            named[0].display(),
+            dir.join("cli-batch-dots.v1.mid").display()
+        ),
+        format!(
+            "{} -> {}",
+            named[1].display(),
+            dir.join("cli-batch-dots.v2.mid").display()
+        ),
+        "converted 2 of 3 files".to_owned(),
+    ];
+    assert_eq!(report.lines().collect::<Vec<_>>(), expected);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        format!(
+            "{}: failed to name the output file: an earlier input is already converted to {}\n",
+            named[2].display(),
+            dir.join("cli-batch-dots.v1.mid").display()
+        )
+    );
+}
+
+#[test]
+fn sidecar_beside_json_output() {
+    let input = tmp("cli-sidecar_json.txt");
+    std::fs::write(&input, "naive-rhythm bpm 120 0 500").unwrap();
+    let output = tmp("cli-sidecar_json.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&input)
+        .arg("--output")
+        .arg(&output)
+        .args(["--emit", "events", "--sidecar"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let events: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    assert!(events.is_array());
+    let sidecar = std::fs::read_to_string(tmp("cli-sidecar_json.sidecar.json")).unwrap();
+    let sidecar: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+    assert_eq!(sidecar["stats"]["notes"], 2);
+}
+
+#[test]
+fn sidecar() {
+    let chart = "naive-rhythm bpm 120 10 490 1000 1500:38";
+    let bytes = convert("sidecar", chart, &["--sidecar"]);
+    let json = std::fs::read_to_string(tmp("cli-sidecar.sidecar.json")).unwrap();
+    let sidecar: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let notes = sidecar["notes"].as_array().unwrap();
+    let midi_notes = note_ons(&Smf::parse(&bytes).unwrap().tracks[1]).len();
+    assert_eq!(notes.len(), midi_notes);
+    assert_eq!(sidecar["stats"]["notes"], midi_notes);
+    let errors: Vec<f64> = notes
+        .iter()
+        .map(|note| note["error_ms"].as_f64().unwrap())
+        .collect();
+    assert_eq!(errors, [10.0, -10.0, 0.0, 0.0]);
+    assert_eq!(sidecar["stats"]["max_error_ms"], 10.0);
+}
+
+#[test]
+fn emit_click() {
+    let args = ["--emit", "click", "--bpm", "120", "--seconds", "4"];
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+    let bytes = run("emit_click", &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    let click = &smf.tracks[1];
+    assert_eq!(note_ons(click), [76, 77, 77, 77, 76, 77, 77, 77]);
+    let alone = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args(["--bpm", "120", "--seconds", "4", "--output"])
+        .arg(tmp("cli-seconds_alone.mid"))
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!alone.success());
+}
+
+#[test]
+fn long_gap() {
+    // 600000 beats apart, 288000000 ticks, more than a delta can hold
+    let chart = "naive-rhythm bpm 60 0 600000000";
+    assert!(!succeeds("long_gap_error", chart, &[]));
+    let bytes = convert("long_gap_split", chart, &["--long-gap", "split"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let mut tick = 0;
+    let mut ons = Vec::new();
+    let mut fillers = 0;
+    for event in &smf.tracks[1] {
+        tick += event.delta.as_int() as u64;
+        match event.kind {
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            } => ons.push(tick),
+            TrackEventKind::Meta(MetaMessage::Text(b"")) => fillers += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(ons, [0, 288_000_000]);
+    assert_eq!(fillers, 1);
+}
+
+#[test]
+fn excerpt() {
+    let chart = "naive-rhythm bpm 120 0 500 1000 1500 2000";
+    let bytes = convert("excerpt", chart, &["--from", "1000", "--to", "2000"]);
+    let smf = Smf::parse(&bytes).unwrap();
+    let onsets: Vec<u32> = note_ticks(&smf.tracks[1])
+        .into_iter()
+        .filter(|&(_, on)| on)
+        .map(|(tick, _)| tick)
+        .collect();
+    assert_eq!(onsets, [0, 480]);
+    // a held note crossing the start is cut to the range, or left out
+    let held = "naive-rhythm bpm 120 500:::1000 1500";
+    let args = ["--from", "1000", "--quantize", "both"];
+    let bytes = convert("excerpt_truncate", held, &args);
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(
+        note_ticks(&smf.tracks[1]),
+        [(0, true), (480, false), (480, true), (960, false)]
+    );
+    let bytes = convert(
+        "excerpt_drop",
+        held,
+        &[&args[..], &["--clip", "drop"]].concat(),
+    );
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(note_ticks(&smf.tracks[1]), [(480, true), (960, false)]);
+    // a bound as late as a key can be is fine under a pre-roll, and one later is refused
+    let early = "naive-rhythm bpm 120 -500 0";
+    assert!(succeeds("excerpt_far", early, &["--to", "4294967295"]));
+    assert!(!succeeds(
+        "excerpt_too_far",
+        early,
+        &["--to", "9223372036854775807"]
+    ));
+}
+
+#[test]
+fn lfo() {
+    // four beats of notes, with the modulation wheel swept every two beats
+    let chart = "naive-rhythm bpm 120 @lfo 1 2 20 0 500 1000 1500";
+    let wheel = |bytes: &[u8]| -> Vec<u8> {
+        let smf = Smf::parse(bytes).unwrap();
+        smf.tracks[1]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::Controller { controller, value },
+                    ..
+                } if controller == 1 => Some(value.as_int()),
+                _ => None,
+            })
+            .collect()
+    };
+    let values = wheel(&convert("lfo", chart, &[]));
+    assert_eq!(values.len(), 4 * 8);
+    assert!(values.iter().all(|value| (44..=84).contains(value)));
+    assert_eq!(values[0], 64);
+    assert_eq!(values.iter().max(), Some(&84));
+    assert_eq!(values.iter().min(), Some(&44));
+    let sparse = wheel(&convert("lfo_sparse", chart, &["--lfo-resolution", "2"]));
+    assert_eq!(sparse.len(), 4 * 2);
+    assert!(!succeeds(
+        "lfo_too_deep",
+        "naive-rhythm bpm 120 @lfo 1 2 64 0",
+        &[]
+    ));
+}
+
+#[test]
+fn merged_duplicates_warn() {
+    let chart = "naive-rhythm bpm 120 0 10 20 500 510 1000";
+    let output = run_output("merged_duplicates", chart, &[]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr,
+        "warning: 3 notes were merged into others on the same beat, the most at 0 ms (2), \
+         500 ms (1)\n"
+    );
+}
+
+#[test]
+fn error_format_json() {
+    let chart = "naive-rhythm bpm 120\n0 500\n1000:300 1500";
+    let output = run_output("error_format_json", chart, &["--error-format", "json"]);
+    assert!(!output.status.success());
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(
+        error,
+        serde_json::json!({
+            "stage": "failed to parse the input",
+            "kind": "BadPitch",
+            "line": 3,
+            "token": "1000:300",
+            "message": "bad pitch on line 3 at \"1000:300\"",
+        })
+    );
+    let missing = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args(["--input", "no-such-chart.txt", "--output"])
+        .arg(tmp("cli-error_format_missing.mid"))
+        .args(["--error-format", "json"])
+        .output()
+        .unwrap();
+    assert!(!missing.status.success());
+    let error: serde_json::Value = serde_json::from_slice(&missing.stderr).unwrap();
+    assert_eq!(error["kind"], "NotFound");
+    let reverse = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .args([
+            "--reverse",
+            "--input",
+            "a.mid",
+            "--input",
+            "b.mid",
+            "--output",
+        ])
+        .arg(tmp("cli-error_format_reverse.txt"))
+        .args(["--error-format", "json"])
+        .output()
+        .unwrap();
+    assert_eq!(reverse.status.code(), Some(1));
+    let error: serde_json::Value = serde_json::from_slice(&reverse.stderr).unwrap();
+    assert_eq!(error["kind"], "ReverseInputs");
+    // usage errors, whether clap finds them or they need the arguments as a whole
+    for (args, kind) in [
+        (
+            &["--bpm", "120", "--seconds", "4"][..],
+            "SecondsWithoutClick",
+        ),
+        (&["--no-such-flag"][..], "UnknownArgument"),
+    ] {
+        let usage = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+            .args(args)
+            .arg("--output")
+            .arg(tmp("cli-error_format_usage.mid"))
+            .args(["--error-format", "json"])
+            .output()
+            .unwrap();
+        assert_eq!(usage.status.code(), Some(1));
+        let error: serde_json::Value = serde_json::from_slice(&usage.stderr).unwrap();
+        assert_eq!(error["stage"], "failed to read the arguments");
+        assert_eq!(error["kind"], kind);
+    }
+}
+
+#[test]
+fn batch_error_format_json() {
+    let dir = tmp("cli-batch_json");
+    std::fs::create_dir_all(&dir).unwrap();
+    let good = tmp("cli-batch_json-good.txt");
+    std::fs::write(&good, "naive-rhythm bpm 120 0 500").unwrap();
+    let bad = tmp("cli-batch_json-bad.txt");
+    std::fs::write(&bad, "naive-rhythm bpm 120 0:300").unwrap();
+    let missing = tmp("cli-batch_json-missing.txt");
+    let output = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&good)
+        .arg("--input")
+        .arg(&bad)
+        .arg("--input")
+        .arg(&missing)
+        .arg("--batch")
+        .arg(&dir)
+        .args(["--error-format", "json", "--quiet"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let errors: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0],
+        serde_json::json!({
+            "input": bad.to_str().unwrap(),
+            "stage": "failed to parse the input",
+            "kind": "BadPitch",
+            "line": 1,
+            "token": "0:300",
+            "message": "bad pitch on line 1 at \"0:300\"",
+        })
+    );
+    assert_eq!(errors[1]["input"], missing.to_str().unwrap());
+    assert_eq!(errors[1]["stage"], "failed to read the input file");
+    assert_eq!(errors[1]["kind"], "NotFound");
+}
+
+#[test]
+fn ticks_never_wrap() {
+    // 2^32 ticks and a little more, which used to wrap round to 617 ms
+    let chart = "naive-rhythm bpm 240 0 2236962653";
+    let failure = |name: &str, chart: &str, args: &[&str]| -> serde_json::Value {
+        let args = [args, &["--error-format", "json"]].concat();
+        let output = run_output(name, chart, &args);
+        assert_eq!(output.status.code(), Some(1));
+        serde_json::from_slice(&output.stderr).unwrap()
+    };
+    assert_eq!(failure("ticks_wrap", chart, &[])["kind"], "GapTooLong");
+    let split = ["--long-gap", "split", "--emit", "events"];
+    let json = convert("ticks_wrap_split", chart, &split);
+    let events: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let ons: Vec<u64> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["type"] == "note_on")
+        .map(|event| event["time_ms"].as_u64().unwrap())
+        .collect();
+    assert_eq!(ons, [0, 2236962750]);
+    // a lead-in or tail past 2^32 ticks, and a key on the last step there is
+    let short = "naive-rhythm bpm 120 0 500";
+    for (name, chart, args) in [
+        ("lead_in_overflow", short, ["--lead-in", "4000000000"]),
+        ("tail_overflow", short, ["--tail", "4000000000"]),
+        (
+            "last_step",
+            "naive-rhythm bpm 60000 0 4294967295",
+            ["--division", "1"],
+        ),
+    ] {
+        assert_eq!(failure(name, chart, &args)["kind"], "GapTooLong");
+        let split = [&args[..], &["--long-gap", "split"]].concat();
+        assert!(succeeds(name, chart, &split), "{}", name);
+    }
+}
+
+#[test]
+fn steps_never_clamp() {
+    // the last key is step 2^33 - 2, which used to be clamped to 2^32 - 1, half the time
+    let chart = "naive-rhythm bpm 60000 0 4294967295";
+    let args = ["--division", "2", "--long-gap", "split", "--emit", "events"];
+    let json = convert("steps_never_clamp", chart, &args);
+    let events: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let ons: Vec<u64> = events
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["type"] == "note_on")
+        .map(|event| event["time_ms"].as_u64().unwrap())
+        .collect();
+    assert_eq!(ons, [0, 4294967295]);
+}
@@ -0,0 +1,178 @@
+//! Calls the library directly, the way a web front-end would.
+
+use naive_rhythm::{
+    convert, length, merge, parse, solve, ChartError, Error, Hold, InputMode, MiddleC, Opts,
+    ParseError, ParseOptions, Place, Quantize, SolveOptions, TieBreak,
+};
+use std::path::Path;
+
+#[test]
+fn convert_chart_string() {
+    let chart = "naive-rhythm\nbpm 120\n0\n500\n1000\n1500\n2000\n";
+    let bytes = convert(chart, &Opts::default()).unwrap();
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/basic.mid");
+    assert_eq!(bytes, std::fs::read(golden).unwrap());
+}
+
+#[test]
+fn convert_headerless_needs_tempo() {
+    let opts = Opts {
+        parse: ParseOptions {
+            headerless: true,
+            tempo: None,
+            ..ParseOptions::default()
+        },
+        ..Opts::default()
+    };
+    let result = convert("0 500", &opts);
+    assert!(matches!(
+        result,
+        Err(Error::Parse(ChartError {
+            error: ParseError::MissingTempo,
+            place: None,
+        }))
+    ));
+}
+
+#[test]
+fn merge_needs_an_input() {
+    assert!(matches!(merge(Vec::new()), Err(ParseError::NoInputs)));
+}
+
+#[test]
+fn negative_keys_shift_the_chart() {
+    let input = parse(
+        "naive-rhythm bpm 120 -200 300 800",
+        &ParseOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(input.shift, 200);
+    let keys: Vec<_> = input.notes.iter().map(|note| note.key).collect();
+    assert_eq!(keys, [0, 500, 1000]);
+    let shifted = convert("naive-rhythm bpm 120 -200 300 800", &Opts::default()).unwrap();
+    let plain = convert("naive-rhythm bpm 120 0 500 1000", &Opts::default()).unwrap();
+    assert_eq!(shifted, plain);
+}
+
+#[test]
+fn quantize_onset_keeps_duration() {
+    let chart = "naive-rhythm bpm 120 480:::300";
+    let solved = |quantize| {
+        let input = parse(chart, &ParseOptions::default()).unwrap();
+        let options = SolveOptions {
+            quantize,
+            ..SolveOptions::default()
+        };
+        solve(input, &options).notes[0]
+    };
+    let onset = solved(Quantize::Onset);
+    assert_eq!((onset.beat, onset.exact), (1, None));
+    assert_eq!(onset.duration, Some(Hold::Ms(300)));
+    let both = solved(Quantize::Both);
+    assert_eq!((both.beat, both.exact), (1, None));
+    assert_eq!(both.duration, Some(Hold::Steps(1)));
+    let duration = solved(Quantize::Duration);
+    assert_eq!(duration.exact, Some(480));
+    assert_eq!(duration.duration, Some(Hold::Steps(1)));
+}
+
+#[test]
+fn extreme_times_are_errors() {
+    let options = ParseOptions::default();
+    for chart in [
+        "naive-rhythm bpm 120 -9223372036854775808",
+        "naive-rhythm bpm 120 @timesig 3/4 at -9223372036854775808",
+        "naive-rhythm bpm 120 @bend -9223372036854775808 0",
+    ] {
+        assert!(parse(chart, &options).is_err(), "{}", chart);
+    }
+}
+
+#[test]
+fn relative_gaps() {
+    let relative = Opts {
+        parse: ParseOptions {
+            input_mode: InputMode::Relative,
+            ..ParseOptions::default()
+        },
+        ..Opts::default()
+    };
+    let gaps = convert("naive-rhythm bpm 120 0 500 500", &relative).unwrap();
+    let times = convert("naive-rhythm bpm 120 0 500 1000", &Opts::default()).unwrap();
+    assert_eq!(gaps, times);
+}
+
+#[test]
+fn tie_break() {
+    // both notes are exactly half a beat from two beats
+    let beats = |tie_break| {
+        let input = parse("naive-rhythm bpm 120 250 750", &ParseOptions::default()).unwrap();
+        let options = SolveOptions {
+            tie_break,
+            ..SolveOptions::default()
+        };
+        let output = solve(input, &options);
+        output.notes.iter().map(|hit| hit.beat).collect::<Vec<_>>()
+    };
+    assert_eq!(beats(TieBreak::Down), [0, 1]);
+    assert_eq!(beats(TieBreak::Up), [1, 2]);
+    assert_eq!(beats(TieBreak::Even), [0, 2]);
+}
+
+#[test]
+fn note_names() {
+    let pitches = |chart: &str, middle_c| {
+        let options = ParseOptions {
+            middle_c,
+            ..ParseOptions::default()
+        };
+        let input = parse(chart, &options)?;
+        Ok::<_, ChartError>(
+            input
+                .notes
+                .iter()
+                .map(|note| note.pitch)
+                .collect::<Vec<_>>(),
+        )
+    };
+    let chart = "naive-rhythm bpm 120 0:C4 500:D#5 1000:Eb3 1500:c-1 2000:38";
+    assert_eq!(pitches(chart, MiddleC::C4).unwrap(), [60, 75, 51, 0, 38]);
+    assert_eq!(pitches(chart, MiddleC::C3).unwrap(), [72, 87, 63, 12, 38]);
+    for bad in ["0:H4", "0:C", "0:C#", "0:Cb-1", "0:C#x"] {
+        let chart = format!("naive-rhythm bpm 120\n0:C4 {}", bad);
+        let error = pitches(&chart, MiddleC::C4).unwrap_err();
+        assert!(matches!(error.error, ParseError::BadPitch));
+        let place = Place {
+            line: 2,
+            token: bad.to_owned(),
+        };
+        assert_eq!(error.place, Some(place));
+    }
+}
+
+#[test]
+fn time_signature_change_to_eighths() {
+    // two bars of 4/4 quarters, then two bars of 6/8 eighths
+    let quarters = (0..8).map(|beat| (beat * 500).to_string());
+    let eighths = (0..=12).map(|eighth| (4000 + eighth * 250).to_string());
+    // off the quarter grid, but nearer the second quarter than the first
+    let off_grid = std::iter::once("260:62".to_string());
+    let keys: Vec<String> = quarters.chain(off_grid).chain(eighths).collect();
+    let chart = format!(
+        "naive-rhythm bpm 120 @timesig 6/8 at 4000 {}",
+        keys.join(" ")
+    );
+    let input = parse(&chart, &ParseOptions::default()).unwrap();
+    let output = solve(input, &SolveOptions::default());
+    assert_eq!(output.division, 2);
+    let beats: Vec<u64> = output.notes.iter().map(|hit| hit.beat).collect();
+    // the 4/4 bars keep snapping to quarters, so 260 lands on 500, not 250
+    let quarters = (0..8).map(|beat| beat * 2);
+    let mut expected: Vec<u64> = quarters.chain([2]).chain(16..=28).collect();
+    expected.sort();
+    assert_eq!(beats, expected);
+    assert_eq!(output.time_signatures[1].0, 16);
+    let length = length(&output);
+    assert_eq!(length.bars, 4.0);
+    assert_eq!(length.seconds, 7.0);
+}
@@ -0,0 +1,65 @@
+//! Runs every chart in `tests/golden` through the binary and compares the
+//! result with the `.mid` file next to it.
+//!
+//! A chart `NAME.txt` may have a `NAME.args` file holding extra command-line
+//! arguments. Set `UPDATE_GOLDEN=1` to rewrite the `.mid` files from the
+//! current output instead of comparing against them.
+
+use midly::Smf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn convert(name: &str) -> Vec<u8> {
+    let dir = golden_dir();
+    let input = dir.join(format!("{}.txt", name));
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("golden-{}.mid", name));
+    let args = match std::fs::read_to_string(dir.join(format!("{}.args", name))) {
+        Ok(args) => args.split_whitespace().map(str::to_owned).collect(),
+        Err(_) => Vec::new(),
+    };
+    let status = Command::new(env!("CARGO_BIN_EXE_naive-rhythm"))
+        .arg("--input")
+        .arg(&input)
+        .arg("--output")
+        .arg(&output)
+        .args(&args)
+        .status()
+        .expect("failed to run naive-rhythm");
+    assert!(status.success(), "naive-rhythm failed on {}", name);
+    std::fs::read(&output).expect("failed to read the output")
+}
+
+fn check(name: &str) {
+    let actual = convert(name);
+    let golden = golden_dir().join(format!("{}.mid", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden, &actual).expect("failed to write the golden file");
+        return;
+    }
+    let expected = std::fs::read(&golden).expect("missing golden file, rerun with UPDATE_GOLDEN=1");
+    // compare events first for a readable diff, then the exact encoding
+    let actual_smf = Smf::parse(&actual).expect("output is not a valid MIDI file");
+    let expected_smf = Smf::parse(&expected).expect("golden is not a valid MIDI file");
+    assert_eq!(actual_smf.header, expected_smf.header, "{}: header", name);
+    assert_eq!(actual_smf.tracks, expected_smf.tracks, "{}: events", name);
+    assert_eq!(actual, expected, "{}: bytes", name);
+}
+
+#[test]
+fn every_chart() {
+    let mut names: Vec<String> = std::fs::read_dir(golden_dir())
+        .expect("failed to list the golden charts")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .map(|path| path.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert!(!names.is_empty(), "no golden charts found");
+    for name in &names {
+        check(name);
+    }
+}
@@ -0,0 +1,99 @@
+//! Turns MIDI files back into charts through the library.
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use naive_rhythm::{import, ImportOptions};
+
+/// A one-note-track file at 120 BPM with a 480 PPQ, holding a NoteOn at each delta.
+fn midi(deltas: &[u32]) -> Vec<u8> {
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(480)),
+    ));
+    let event = |delta, kind| TrackEvent {
+        delta: u28::new(delta),
+        kind,
+    };
+    smf.tracks.push(vec![
+        event(
+            0,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+        ),
+        event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+    ]);
+    let mut notes: Vec<TrackEvent> = deltas
+        .iter()
+        .map(|&delta| {
+            let message = MidiMessage::NoteOn {
+                key: u7::new(38),
+                vel: u7::new(100),
+            };
+            let channel = u4::new(0);
+            event(delta, TrackEventKind::Midi { channel, message })
+        })
+        .collect();
+    notes.push(event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    smf.tracks.push(notes);
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn import_notes() {
+    let chart = import(&midi(&[0, 480, 480]), &ImportOptions::default()).unwrap();
+    assert_eq!(
+        chart,
+        "naive-rhythm\nbpm 120\n0:38:100\n500:38:100\n1000:38:100\n"
+    );
+}
+
+#[test]
+fn label_triplets() {
+    let options = ImportOptions {
+        label_durations: true,
+    };
+    let chart = import(&midi(&[0, 160, 160, 160, 240]), &options).unwrap();
+    let labels: Vec<_> = chart
+        .lines()
+        .skip(2)
+        .map(|line| line.split_once(" # ").map(|(_, label)| label))
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            None,
+            Some("eighth triplet"),
+            Some("eighth triplet"),
+            Some("eighth triplet"),
+            Some("eighth"),
+        ]
+    );
+}
+
+/// An `MTrk` chunk around raw track bytes.
+fn chunk(events: &[u8]) -> Vec<u8> {
+    let mut bytes = b"MTrk".to_vec();
+    bytes.extend((events.len() as u32).to_be_bytes());
+    bytes.extend(events);
+    bytes
+}
+
+#[test]
+fn import_every_track() {
+    // format 1, three tracks, 480 PPQ
+    let mut bytes = b"MThd\x00\x00\x00\x06\x00\x01\x00\x03\x01\xe0".to_vec();
+    // tempo 500000 and a text event, with no EndOfTrack
+    bytes.extend(chunk(b"\x00\xff\x51\x03\x07\xa1\x20\x00\xff\x01\x02hi"));
+    // kick on beats 0 and 2 with a controller in between
+    bytes.extend(chunk(
+        b"\x00\x90\x24\x64\x83\x60\x80\x24\x00\x00\xb0\x07\x64\x83\x60\x90\x24\x64\x00\xff\x2f\x00",
+    ));
+    // snare on beat 1 on another channel, ended with running status and no EndOfTrack
+    bytes.extend(chunk(b"\x83\x60\x91\x26\x50\x83\x60\x26\x00"));
+    let chart = import(&bytes, &ImportOptions::default()).unwrap();
+    assert_eq!(
+        chart,
+        "naive-rhythm\nbpm 120\n0:36:100\n500:38:80\n1000:36:100\n"
+    );
+}
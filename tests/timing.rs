@@ -0,0 +1,59 @@
+//! Checks the tempo and tick conversions on their own.
+
+use naive_rhythm::timing::{beat_ms, ms_to_ticks, steps_to_ticks, tempo_us, ticks_to_ms};
+
+#[test]
+fn tempo_of_bpm() {
+    assert_eq!(tempo_us(60), 1_000_000);
+    assert_eq!(tempo_us(120), 500_000);
+    assert_eq!(tempo_us(97), 618_556);
+    assert_eq!(tempo_us(240), 250_000);
+}
+
+#[test]
+fn beat_length() {
+    assert_eq!(beat_ms(tempo_us(60)), 1000.0);
+    assert_eq!(beat_ms(tempo_us(120)), 500.0);
+    assert_eq!(beat_ms(tempo_us(200)), 300.0);
+    assert_eq!(beat_ms(618_556), 618.556);
+}
+
+#[test]
+fn milliseconds_to_ticks() {
+    // one beat is one quarter note whatever the tempo
+    for bpm in [60, 97, 120, 200, 240] {
+        for ppq in [96, 480, 960] {
+            let ms = beat_ms(tempo_us(bpm)).round() as u64;
+            let ticks = ms_to_ticks(ms, tempo_us(bpm), ppq) as i64;
+            assert!((ticks - ppq as i64).abs() <= 1, "{} bpm, {} ppq", bpm, ppq);
+        }
+    }
+    assert_eq!(ms_to_ticks(250, tempo_us(120), 480), 240);
+    assert_eq!(ms_to_ticks(1000, tempo_us(60), 96), 96);
+    assert_eq!(ms_to_ticks(0, tempo_us(200), 480), 0);
+}
+
+#[test]
+fn ticks_to_milliseconds() {
+    assert_eq!(ticks_to_ms(480, tempo_us(120), 480), 500);
+    assert_eq!(ticks_to_ms(160, tempo_us(120), 480), 167);
+    assert_eq!(ticks_to_ms(96, tempo_us(60), 96), 1000);
+    for bpm in [60, 97, 120, 200] {
+        for ppq in [96, 480, 960] {
+            let tempo = tempo_us(bpm);
+            assert_eq!(
+                ms_to_ticks(ticks_to_ms(ppq as u64 * 4, tempo, ppq), tempo, ppq),
+                ppq as u64 * 4
+            );
+        }
+    }
+}
+
+#[test]
+fn steps_of_a_division() {
+    assert_eq!(steps_to_ticks(4, 1, 480), 1920);
+    assert_eq!(steps_to_ticks(1, 2, 480), 240);
+    assert_eq!(steps_to_ticks(1, 3, 480), 160);
+    assert_eq!(steps_to_ticks(1, 7, 96), 14);
+    assert_eq!(steps_to_ticks(3, 8, 960), 360);
+}